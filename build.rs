@@ -0,0 +1,214 @@
+//! Embeds everything under `static/` into the binary
+//!
+//! Generates `$OUT_DIR/assets.rs` with one `Asset` per file, including a
+//! content hash used to build cache-busting URLs. Also sets the build info
+//! env vars (`GIT_REVISION`, `BUILD_TIME`, `BUILD_FEATURES`) for `/version`.
+
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{env, fs, io};
+
+use sha2::{Digest, Sha256};
+
+/// Used on every page, so a build without them would be broken
+const REQUIRED: &[&str] = &["vendor/htmx/htmx.min.js"];
+
+/// Extensions of files worth precompressing; images etc. are already compressed
+const COMPRESSIBLE: &[&str] = &["css", "js", "html", "txt", "json", "svg", "wasm"];
+
+fn main() -> io::Result<()> {
+    emit_build_info();
+
+    let static_dir =
+        Path::new(&env::var("CARGO_MANIFEST_DIR").expect("set by cargo")).join("static");
+    println!("cargo:rerun-if-changed={}", static_dir.display());
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").expect("set by cargo"));
+    let compressed_dir = out_dir.join("compressed");
+
+    let mut files = vec![];
+    collect_files(&static_dir, &mut files)?;
+    files.sort();
+
+    let files = files
+        .into_iter()
+        .map(|file| {
+            let rel_path = file
+                .strip_prefix(&static_dir)
+                .expect("file is inside static dir")
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            (file, rel_path)
+        })
+        .collect::<Vec<_>>();
+    for required in REQUIRED {
+        if !files.iter().any(|(_, rel_path)| rel_path == required) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("static/{required} is missing (see `just vendor-htmx`)"),
+            ));
+        }
+    }
+
+    let mut out = String::from("pub static ASSETS: &[Asset] = &[\n");
+    for (file, rel_path) in files {
+        let content = fs::read(&file)?;
+        let modified = fs::metadata(&file)?
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let hash = Sha256::digest(&content);
+        let hash = hash[..8].iter().fold(String::new(), |mut s, b| {
+            write!(s, "{b:02x}").expect("can't fail");
+            s
+        });
+
+        let (mut brotli, mut gzip) = (None, None);
+        let ext = file.extension().map(|e| e.to_string_lossy().into_owned());
+        if ext.is_some_and(|ext| COMPRESSIBLE.contains(&ext.as_str())) {
+            brotli = write_if_smaller(
+                &compressed_dir.join(format!("{rel_path}.br")),
+                &compress_brotli(&content)?,
+                &content,
+            )?;
+            gzip = write_if_smaller(
+                &compressed_dir.join(format!("{rel_path}.gz")),
+                &compress_gzip(&content)?,
+                &content,
+            )?;
+        }
+
+        writeln!(
+            out,
+            "    Asset {{ path: {rel_path:?}, hash: {hash:?}, modified_unix_secs: {modified}, content: include_bytes!({:?}), brotli: {}, gzip: {} }},",
+            file.display(),
+            include_opt(brotli.as_deref()),
+            include_opt(gzip.as_deref()),
+        )
+        .expect("can't fail");
+    }
+    out.push_str("];\n");
+
+    fs::write(out_dir.join("assets.rs"), out)
+}
+
+fn emit_build_info() {
+    // Builds without a git checkout (e.g. in the nix sandbox) can pass it in
+    println!("cargo:rerun-if-env-changed=GIT_REVISION");
+    // Missing paths would make cargo rerun this on every build
+    for path in [".git/HEAD", ".git/refs/heads"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={path}");
+        }
+    }
+    let revision = env::var("GIT_REVISION").ok().or_else(|| {
+        let output = Command::new("git")
+            .args(["rev-parse", "HEAD"])
+            .output()
+            .ok()
+            .filter(|o| o.status.success())?;
+        Some(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    });
+    println!(
+        "cargo:rustc-env=GIT_REVISION={}",
+        revision.as_deref().unwrap_or("unknown")
+    );
+
+    // Respect reproducible builds
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let build_time = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default()
+        });
+    println!("cargo:rustc-env=BUILD_TIME={}", rfc3339(build_time));
+
+    let mut features = env::vars()
+        .filter_map(|(k, _)| k.strip_prefix("CARGO_FEATURE_").map(|f| f.to_lowercase()))
+        .collect::<Vec<_>>();
+    features.sort();
+    println!("cargo:rustc-env=BUILD_FEATURES={}", features.join(","));
+}
+
+/// `1970-01-01T00:00:00Z`, without pulling in a date crate
+fn rfc3339(unix_secs: u64) -> String {
+    let (days, secs) = (unix_secs / 86400, unix_secs % 86400);
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+fn compress_brotli(content: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = vec![];
+    {
+        let mut writer = brotli::CompressorWriter::new(&mut out, 4096, 11, 22);
+        writer.write_all(content)?;
+    }
+    Ok(out)
+}
+
+fn compress_gzip(content: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::best());
+    encoder.write_all(content)?;
+    encoder.finish()
+}
+
+/// Store a compressed variant, unless it wouldn't save anything
+fn write_if_smaller(
+    path: &Path,
+    compressed: &[u8],
+    original: &[u8],
+) -> io::Result<Option<PathBuf>> {
+    if original.len() <= compressed.len() {
+        return Ok(None);
+    }
+    fs::create_dir_all(path.parent().expect("has a parent"))?;
+    fs::write(path, compressed)?;
+    Ok(Some(path.to_owned()))
+}
+
+fn include_opt(path: Option<&Path>) -> String {
+    match path {
+        Some(path) => format!("Some(include_bytes!({:?}))", path.display()),
+        None => "None".into(),
+    }
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,96 @@
+//! Static assets embedded from `static/` at build time (see `build.rs`)
+//!
+//! Assets are served under `/static/<hash>/<path>`, so their URLs change
+//! whenever their content does and they can be cached forever. In
+//! [`dev`](crate::dev) mode they are read from disk under `/static/dev/<path>`
+//! instead.
+
+use std::time::SystemTime;
+
+use crate::compression::{AcceptEncoding, Encoding};
+use crate::route::Route;
+use crate::{conditional, dev};
+
+pub struct Asset {
+    /// Path relative to `static/`, e.g. `vendor/htmx/htmx.min.js`
+    pub path: &'static str,
+    /// Shortened hex-encoded sha256 of the content
+    pub hash: &'static str,
+    pub modified_unix_secs: u64,
+    pub content: &'static [u8],
+    /// Precompressed variants, only if they are smaller than `content`
+    pub brotli: Option<&'static [u8]>,
+    pub gzip: Option<&'static [u8]>,
+}
+
+include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+
+impl Asset {
+    pub fn url(&self) -> String {
+        if dev::enabled() {
+            return Route::static_asset(DEV_HASH, self.path).to_string();
+        }
+        Route::static_asset(self.hash, self.path).to_string()
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        content_type(self.path)
+    }
+
+    /// The best representation for the client, and its encoding
+    pub fn negotiate(&self, accept: AcceptEncoding) -> (Option<Encoding>, &'static [u8]) {
+        match accept.preferred(self.brotli.is_some(), self.gzip.is_some()) {
+            Some(Encoding::Brotli) => (Some(Encoding::Brotli), self.brotli.expect("checked")),
+            Some(Encoding::Gzip) => (Some(Encoding::Gzip), self.gzip.expect("checked")),
+            None => (None, self.content),
+        }
+    }
+
+    pub fn has_variants(&self) -> bool {
+        self.brotli.is_some() || self.gzip.is_some()
+    }
+
+    pub fn modified(&self) -> SystemTime {
+        conditional::unix_secs_to_system_time(self.modified_unix_secs)
+    }
+}
+
+/// Placeholder for the hash part of URLs in dev mode
+pub const DEV_HASH: &str = "dev";
+
+pub fn content_type(path: &str) -> &'static str {
+    let ext = path.rsplit_once('.').map(|(_, ext)| ext).unwrap_or("");
+    match ext {
+        "css" => "text/css",
+        "js" => "text/javascript",
+        "html" => "text/html",
+        "txt" => "text/plain",
+        "json" => "application/json",
+        "gif" => "image/gif",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "webp" => "image/webp",
+        "woff2" => "font/woff2",
+        "wasm" => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+pub fn get(path: &str) -> Option<&'static Asset> {
+    ASSETS.iter().find(|asset| asset.path == path)
+}
+
+/// Content-hashed URL of an asset from `static/`
+///
+/// Panics if there is no such asset, as that's a bug in the calling code.
+pub fn url(path: &str) -> String {
+    if dev::enabled() {
+        // might be a file added after the build
+        return Route::static_asset(DEV_HASH, path).to_string();
+    }
+    get(path)
+        .unwrap_or_else(|| panic!("Unknown static asset: {path}"))
+        .url()
+}
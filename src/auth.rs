@@ -0,0 +1,521 @@
+//! Accounts, passwords and login sessions
+//!
+//! Passwords are hashed with argon2id. New users are viewers; other roles are
+//! handed out with the `set-role` command. A session is a random token kept in
+//! a cookie; only its SHA-256 is stored, so a copy of the database (e.g. a
+//! backup) can't be used to log in as anyone.
+
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use anyhow::Context;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use base64::Engine;
+use redb::{
+    MultimapTableDefinition, ReadableMultimapTable, ReadableTable, TableDefinition,
+    WriteTransaction,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::metrics::{self, TxKind};
+use crate::util::unix_now;
+
+/// User id -> [`User`], as JSON
+pub const USERS_TABLE: TableDefinition<u64, &str> = TableDefinition::new("users");
+/// Normalized username -> user id
+pub const USERNAMES_TABLE: TableDefinition<&str, u64> = TableDefinition::new("usernames");
+/// SHA-256 of the session token (hex) -> [`Session`], as JSON
+pub const SESSIONS_TABLE: TableDefinition<&str, &str> = TableDefinition::new("sessions");
+/// User id -> keys of their [`SESSIONS_TABLE`] entries
+pub const USER_SESSIONS_TABLE: MultimapTableDefinition<u64, &str> =
+    MultimapTableDefinition::new("user_sessions");
+/// Expiry (unix time) -> keys of [`SESSIONS_TABLE`] entries
+pub const SESSION_EXPIRY_TABLE: MultimapTableDefinition<u64, &str> =
+    MultimapTableDefinition::new("session_expiry");
+
+pub const SESSION_COOKIE: &str = "session";
+pub const SESSION_MAX_AGE_SECS: u64 = 30 * 24 * 60 * 60;
+
+pub const USERNAME_LEN: RangeInclusive<usize> = 3..=32;
+/// The upper bound keeps hashing cheap for whoever sends a huge "password"
+pub const PASSWORD_LEN: RangeInclusive<usize> = 8..=128;
+
+/// What a user may do; each role may do everything the ones before it may
+#[derive(
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Debug,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Viewer,
+    Editor,
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Admin => "admin",
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct User {
+    pub username: String,
+    /// PHC string, including the salt and parameters
+    pub password_hash: String,
+    pub created_at: u64,
+    /// Missing for users created before there were roles
+    #[serde(default)]
+    pub role: Role,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Session {
+    user_id: u64,
+    expires_at: u64,
+}
+
+/// The user a request is logged in as
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CurrentUser {
+    pub id: u64,
+    pub username: String,
+    pub role: Role,
+}
+
+impl CurrentUser {
+    pub fn has_role(&self, role: Role) -> bool {
+        role <= self.role
+    }
+}
+
+/// Usernames are case-insensitive
+pub fn normalize_username(username: &str) -> String {
+    username.trim().to_lowercase()
+}
+
+/// Checks a [normalized](normalize_username) username; the error is meant for
+/// the user
+pub fn validate_username(username: &str) -> Result<(), String> {
+    if !USERNAME_LEN.contains(&username.chars().count()) {
+        return Err(format!(
+            "Must be {} to {} characters long",
+            USERNAME_LEN.start(),
+            USERNAME_LEN.end()
+        ));
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "_-.".contains(c))
+    {
+        return Err("Only letters, digits, `_`, `-` and `.` are allowed".to_owned());
+    }
+    Ok(())
+}
+
+/// The error is meant for the user
+pub fn validate_password(password: &str) -> Result<(), String> {
+    if !PASSWORD_LEN.contains(&password.chars().count()) {
+        return Err(format!(
+            "Must be {} to {} characters long",
+            PASSWORD_LEN.start(),
+            PASSWORD_LEN.end()
+        ));
+    }
+    Ok(())
+}
+
+pub fn hash_password(password: &str) -> anyhow::Result<String> {
+    let mut salt = [0u8; 16];
+    getrandom::getrandom(&mut salt).context("Failed to generate a salt")?;
+    let salt = SaltString::encode_b64(&salt)
+        .map_err(|err| anyhow::format_err!("Failed to encode salt: {err}"))?;
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|err| anyhow::format_err!("Failed to hash password: {err}"))?;
+    Ok(hash.to_string())
+}
+
+pub fn verify_password(password: &str, hash: &str) -> anyhow::Result<bool> {
+    let hash = PasswordHash::new(hash)
+        .map_err(|err| anyhow::format_err!("Invalid password hash: {err}"))?;
+    Ok(Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .is_ok())
+}
+
+/// Hash of a password nobody has, checked against when the username doesn't
+/// exist, so unknown usernames take as long as wrong passwords
+fn dummy_hash() -> anyhow::Result<&'static str> {
+    static HASH: OnceLock<String> = OnceLock::new();
+    if let Some(hash) = HASH.get() {
+        return Ok(hash);
+    }
+    let hash = hash_password("not anybody's password")?;
+    Ok(HASH.get_or_init(|| hash))
+}
+
+/// Returns the new user's id, or `None` if the username is taken
+pub fn create_user(
+    db: &redb::Database,
+    username: &str,
+    password: &str,
+) -> anyhow::Result<Option<u64>> {
+    // Before the transaction, which would be held up by the hashing
+    let user = User {
+        username: username.to_owned(),
+        password_hash: hash_password(password)?,
+        created_at: unix_now(),
+        role: Role::Viewer,
+    };
+
+    let start = Instant::now();
+    let tx = db.begin_write()?;
+    let id = {
+        let mut usernames = tx.open_table(USERNAMES_TABLE)?;
+        if usernames.get(username)?.is_some() {
+            return Ok(None);
+        }
+        let mut users = tx.open_table(USERS_TABLE)?;
+        let id = match users.iter()?.next_back() {
+            Some(entry) => entry?.0.value() + 1,
+            None => 1,
+        };
+        users.insert(id, serde_json::to_string(&user)?.as_str())?;
+        usernames.insert(username, id)?;
+        id
+    };
+    tx.commit()?;
+    metrics::get().observe_db_transaction(TxKind::Write, "create_user", start.elapsed());
+    Ok(Some(id))
+}
+
+pub fn find_user(db: &redb::Database, username: &str) -> anyhow::Result<Option<(u64, User)>> {
+    let start = Instant::now();
+    let tx = db.begin_read()?;
+    let Some(id) = tx
+        .open_table(USERNAMES_TABLE)?
+        .get(username)?
+        .map(|v| v.value())
+    else {
+        return Ok(None);
+    };
+    let user = get_user(&tx.open_table(USERS_TABLE)?, id)?;
+    metrics::get().observe_db_transaction(TxKind::Read, "find_user", start.elapsed());
+    Ok(Some((id, user)))
+}
+
+pub fn find_user_by_id(db: &redb::Database, id: u64) -> anyhow::Result<Option<User>> {
+    let start = Instant::now();
+    let tx = db.begin_read()?;
+    let users = tx.open_table(USERS_TABLE)?;
+    if users.get(id)?.is_none() {
+        return Ok(None);
+    }
+    let user = get_user(&users, id)?;
+    metrics::get().observe_db_transaction(TxKind::Read, "find_user", start.elapsed());
+    Ok(Some(user))
+}
+
+fn get_user(users: &impl ReadableTable<u64, &'static str>, id: u64) -> anyhow::Result<User> {
+    let user = users
+        .get(id)?
+        .with_context(|| format!("User {id} is missing"))?;
+    serde_json::from_str(user.value()).with_context(|| format!("Invalid user {id}"))
+}
+
+/// The user's id, if the password is right
+pub fn check_login(
+    db: &redb::Database,
+    username: &str,
+    password: &str,
+) -> anyhow::Result<Option<u64>> {
+    match find_user(db, username)? {
+        Some((id, user)) => Ok(verify_password(password, &user.password_hash)?.then_some(id)),
+        None => {
+            verify_password(password, dummy_hash()?)?;
+            Ok(None)
+        }
+    }
+}
+
+/// Change a user's password, logging them out everywhere
+pub fn set_password(db: &redb::Database, user_id: u64, password: &str) -> anyhow::Result<()> {
+    let password_hash = hash_password(password)?;
+
+    let start = Instant::now();
+    let tx = db.begin_write()?;
+    {
+        let mut users = tx.open_table(USERS_TABLE)?;
+        let user = User {
+            password_hash,
+            ..get_user(&users, user_id)?
+        };
+        users.insert(user_id, serde_json::to_string(&user)?.as_str())?;
+    }
+    let theirs = tx
+        .open_multimap_table(USER_SESSIONS_TABLE)?
+        .get(user_id)?
+        .map(|key| Ok(key?.value().to_owned()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    for key in theirs {
+        remove_session(&tx, &key)?;
+    }
+    tx.commit()?;
+    metrics::get().observe_db_transaction(TxKind::Write, "set_password", start.elapsed());
+    Ok(())
+}
+
+/// `Set-Cookie` value for a session token; an empty token with a `max_age_secs`
+/// of 0 removes the cookie
+///
+/// `SameSite=Lax` keeps other sites from submitting forms as the user.
+pub fn session_cookie(token: &str, max_age_secs: u64, secure: bool) -> String {
+    format!(
+        "{SESSION_COOKIE}={token}; Path=/; Max-Age={max_age_secs}; HttpOnly; SameSite=Lax{}",
+        if secure { "; Secure" } else { "" }
+    )
+}
+
+pub fn set_role(db: &redb::Database, username: &str, role: Role) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let tx = db.begin_write()?;
+    let id = tx
+        .open_table(USERNAMES_TABLE)?
+        .get(username)?
+        .with_context(|| format!("No user named `{username}`"))?
+        .value();
+    write_role(&tx, id, role)?;
+    tx.commit()?;
+    metrics::get().observe_db_transaction(TxKind::Write, "set_role", start.elapsed());
+    Ok(())
+}
+
+pub fn set_role_by_id(db: &redb::Database, id: u64, role: Role) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let tx = db.begin_write()?;
+    write_role(&tx, id, role)?;
+    tx.commit()?;
+    metrics::get().observe_db_transaction(TxKind::Write, "set_role", start.elapsed());
+    Ok(())
+}
+
+fn write_role(tx: &WriteTransaction, id: u64, role: Role) -> anyhow::Result<()> {
+    let mut users = tx.open_table(USERS_TABLE)?;
+    let user = User {
+        role,
+        ..get_user(&users, id)?
+    };
+    users.insert(id, serde_json::to_string(&user)?.as_str())?;
+    Ok(())
+}
+
+fn token_key(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Start a session for `user_id`, returning the token for the cookie
+///
+/// Expired sessions are pruned along the way.
+pub fn create_session(db: &redb::Database, user_id: u64) -> anyhow::Result<String> {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).context("Failed to generate a session token")?;
+    let token = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes);
+    let now = unix_now();
+    let session = Session {
+        user_id,
+        expires_at: now + SESSION_MAX_AGE_SECS,
+    };
+    let key = token_key(&token);
+
+    let start = Instant::now();
+    let tx = db.begin_write()?;
+    remove_expired_sessions(&tx, now)?;
+    tx.open_table(SESSIONS_TABLE)?
+        .insert(key.as_str(), serde_json::to_string(&session)?.as_str())?;
+    tx.open_multimap_table(USER_SESSIONS_TABLE)?
+        .insert(user_id, key.as_str())?;
+    tx.open_multimap_table(SESSION_EXPIRY_TABLE)?
+        .insert(session.expires_at, key.as_str())?;
+    tx.commit()?;
+    metrics::get().observe_db_transaction(TxKind::Write, "create_session", start.elapsed());
+    Ok(token)
+}
+
+/// The user logged in with `token`, unless the session expired or ended
+pub fn session_user(db: &redb::Database, token: &str) -> anyhow::Result<Option<CurrentUser>> {
+    let start = Instant::now();
+    let tx = db.begin_read()?;
+    let sessions = tx.open_table(SESSIONS_TABLE)?;
+    let Some(session) = sessions.get(token_key(token).as_str())? else {
+        return Ok(None);
+    };
+    let session: Session = serde_json::from_str(session.value()).context("Invalid session")?;
+    if session.expires_at <= unix_now() {
+        return Ok(None);
+    }
+    let user = get_user(&tx.open_table(USERS_TABLE)?, session.user_id)?;
+    metrics::get().observe_db_transaction(TxKind::Read, "session_user", start.elapsed());
+    Ok(Some(CurrentUser {
+        id: session.user_id,
+        username: user.username,
+        role: user.role,
+    }))
+}
+
+pub fn delete_session(db: &redb::Database, token: &str) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let tx = db.begin_write()?;
+    remove_session(&tx, &token_key(token))?;
+    tx.commit()?;
+    metrics::get().observe_db_transaction(TxKind::Write, "delete_session", start.elapsed());
+    Ok(())
+}
+
+fn remove_expired_sessions(tx: &WriteTransaction, now: u64) -> anyhow::Result<()> {
+    let expired = tx
+        .open_multimap_table(SESSION_EXPIRY_TABLE)?
+        .range(..=now)?
+        .map(|entry| {
+            let (_, keys) = entry?;
+            keys.map(|key| Ok(key?.value().to_owned()))
+                .collect::<anyhow::Result<Vec<_>>>()
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    for key in expired.concat() {
+        remove_session(tx, &key)?;
+    }
+    Ok(())
+}
+
+/// Remove a session by its key in [`SESSIONS_TABLE`], along with its index
+/// entries
+fn remove_session(tx: &WriteTransaction, key: &str) -> anyhow::Result<()> {
+    let session: Session = {
+        let mut sessions = tx.open_table(SESSIONS_TABLE)?;
+        let Some(value) = sessions.remove(key)? else {
+            return Ok(());
+        };
+        let value = value.value().to_owned();
+        serde_json::from_str(&value).context("Invalid session")?
+    };
+    tx.open_multimap_table(USER_SESSIONS_TABLE)?
+        .remove(session.user_id, key)?;
+    tx.open_multimap_table(SESSION_EXPIRY_TABLE)?
+        .remove(session.expires_at, key)?;
+    Ok(())
+}
+
+/// Index the sessions started before [`USER_SESSIONS_TABLE`] and
+/// [`SESSION_EXPIRY_TABLE`] existed
+pub fn index_sessions(tx: &WriteTransaction) -> anyhow::Result<()> {
+    let sessions = tx.open_table(SESSIONS_TABLE)?;
+    let mut by_user = tx.open_multimap_table(USER_SESSIONS_TABLE)?;
+    let mut by_expiry = tx.open_multimap_table(SESSION_EXPIRY_TABLE)?;
+    for entry in sessions.iter()? {
+        let (key, value) = entry?;
+        let session: Session = serde_json::from_str(value.value()).context("Invalid session")?;
+        by_user.insert(session.user_id, key.value())?;
+        by_expiry.insert(session.expires_at, key.value())?;
+    }
+    Ok(())
+}
+
+#[test]
+fn auth_test() {
+    let path = std::env::temp_dir().join(format!("htmx-demo-auth-test-{}", std::process::id()));
+    let db = redb::Database::create(&path).unwrap();
+    crate::migrations::run(&db, false).unwrap();
+
+    let hash = hash_password("hunter22").unwrap();
+    assert!(hash.starts_with("$argon2id$"));
+    assert_ne!(hash, hash_password("hunter22").unwrap());
+    assert!(verify_password("hunter22", &hash).unwrap());
+    assert!(!verify_password("hunter23", &hash).unwrap());
+
+    assert_eq!(normalize_username(" Alice "), "alice");
+    assert!(validate_username("alice_1").is_ok());
+    assert!(validate_username("al").is_err());
+    assert!(validate_username("al ice").is_err());
+    assert!(validate_password("short").is_err());
+
+    let alice = create_user(&db, "alice", "hunter22").unwrap().unwrap();
+    let bob = create_user(&db, "bob", "swordfish").unwrap().unwrap();
+    assert_ne!(alice, bob);
+    assert_eq!(create_user(&db, "alice", "whatever1").unwrap(), None);
+
+    assert_eq!(check_login(&db, "alice", "hunter22").unwrap(), Some(alice));
+    assert_eq!(check_login(&db, "alice", "swordfish").unwrap(), None);
+    assert_eq!(check_login(&db, "carol", "hunter22").unwrap(), None);
+
+    let token = create_session(&db, alice).unwrap();
+    let bobs = create_session(&db, bob).unwrap();
+    assert_eq!(
+        session_user(&db, &token).unwrap(),
+        Some(CurrentUser {
+            id: alice,
+            username: "alice".to_owned(),
+            role: Role::Viewer,
+        })
+    );
+    set_role(&db, "alice", Role::Editor).unwrap();
+    let user = session_user(&db, &token).unwrap().unwrap();
+    assert!(user.has_role(Role::Viewer) && user.has_role(Role::Editor));
+    assert!(!user.has_role(Role::Admin));
+    assert!(set_role(&db, "carol", Role::Admin).is_err());
+    assert_eq!(session_user(&db, "made-up").unwrap(), None);
+
+    delete_session(&db, &token).unwrap();
+    assert_eq!(session_user(&db, &token).unwrap(), None);
+
+    // Expired sessions are removed when starting new ones
+    {
+        let tx = db.begin_write().unwrap();
+        remove_expired_sessions(&tx, unix_now() + SESSION_MAX_AGE_SECS).unwrap();
+        tx.commit().unwrap();
+    }
+    assert_eq!(session_user(&db, &bobs).unwrap(), None);
+    let bobs = create_session(&db, bob).unwrap();
+    let other = create_session(&db, alice).unwrap();
+    let tx = db.begin_read().unwrap();
+    assert_eq!(tx.open_table(SESSIONS_TABLE).unwrap().len().unwrap(), 2);
+    assert_eq!(
+        tx.open_multimap_table(SESSION_EXPIRY_TABLE)
+            .unwrap()
+            .len()
+            .unwrap(),
+        2
+    );
+    drop(tx);
+
+    // Changing the password ends every session of that user, only
+    set_password(&db, alice, "correct horse").unwrap();
+    assert_eq!(session_user(&db, &other).unwrap(), None);
+    assert!(session_user(&db, &bobs).unwrap().is_some());
+    assert_eq!(check_login(&db, "alice", "hunter22").unwrap(), None);
+    assert_eq!(
+        check_login(&db, "alice", "correct horse").unwrap(),
+        Some(alice)
+    );
+
+    drop(db);
+    std::fs::remove_file(&path).unwrap();
+}
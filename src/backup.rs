@@ -0,0 +1,404 @@
+//! Online backups of the database
+//!
+//! A backup is a separate redb database with a copy of every table, taken
+//! from a single read transaction, so it's consistent even while the server
+//! keeps writing.
+//!
+//! The running server holds the data directory, so it takes backups requested
+//! by the `backup` command itself, over a socket in the data directory.
+
+use std::fs::{self, Permissions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use redb::{
+    MultimapTableDefinition, MultimapTableHandle, ReadTransaction, ReadableMultimapTable,
+    ReadableTable, RedbKey, RedbValue, TableDefinition, TableHandle, WriteTransaction,
+};
+use tracing::{info, warn};
+
+use crate::data_dir::DataDir;
+use crate::db::Db;
+use crate::metrics::{self, TxKind};
+use crate::util::unix_now;
+use crate::{auth, migrations, persist};
+
+const BACKUP_FILE_PREFIX: &str = "backup-";
+const BACKUP_FILE_SUFFIX: &str = ".redb";
+const SOCKET_FILE_NAME: &str = "backup.sock";
+
+/// redb silently initializes a fresh database over any file that doesn't start
+/// with this, so it needs to be checked before opening
+const REDB_MAGIC: &[u8] = b"redb\x1A\x0A\xA9\x0D\x0A";
+
+/// Copy every table from `src` into `dst`, or with `dst` set to `None` just
+/// read everything
+///
+/// Add new tables here, or backups will refuse to run.
+fn copy_tables(src: &ReadTransaction, dst: Option<&WriteTransaction>) -> anyhow::Result<()> {
+    let known = [
+        copy_table(src, dst, migrations::METADATA_TABLE)?,
+        copy_table(src, dst, persist::COUNTERS_TABLE)?,
+        copy_table(src, dst, persist::RATE_LIMIT_SNAPSHOT_TABLE)?,
+        copy_table(src, dst, auth::USERS_TABLE)?,
+        copy_table(src, dst, auth::USERNAMES_TABLE)?,
+        copy_table(src, dst, auth::SESSIONS_TABLE)?,
+        copy_multimap_table(src, dst, auth::USER_SESSIONS_TABLE)?,
+        copy_multimap_table(src, dst, auth::SESSION_EXPIRY_TABLE)?,
+    ];
+
+    let tables = src.list_tables()?.map(|table| table.name().to_owned());
+    let multimap_tables = src
+        .list_multimap_tables()?
+        .map(|table| table.name().to_owned());
+    for table in tables.chain(multimap_tables) {
+        if !known.contains(&table) {
+            bail!("Table `{table}` is not known to backups; add it to `backup::copy_tables`");
+        }
+    }
+    Ok(())
+}
+
+/// Returns the name of the table
+fn copy_table<K: RedbKey + 'static, V: RedbValue + 'static>(
+    src: &ReadTransaction,
+    dst: Option<&WriteTransaction>,
+    def: TableDefinition<K, V>,
+) -> anyhow::Result<String> {
+    let name = def.name().to_owned();
+    let src_table = match src.open_table(def) {
+        Ok(table) => table,
+        Err(redb::TableError::TableDoesNotExist(_)) => return Ok(name),
+        Err(err) => return Err(err.into()),
+    };
+    let mut dst_table = dst.map(|dst| dst.open_table(def)).transpose()?;
+    for entry in src_table.iter()? {
+        let (k, v) = entry?;
+        if let Some(dst_table) = dst_table.as_mut() {
+            dst_table.insert(k.value(), v.value())?;
+        }
+    }
+    Ok(name)
+}
+
+/// Like [`copy_table`], for multimap tables
+fn copy_multimap_table<K: RedbKey + 'static, V: RedbKey + 'static>(
+    src: &ReadTransaction,
+    dst: Option<&WriteTransaction>,
+    def: MultimapTableDefinition<K, V>,
+) -> anyhow::Result<String> {
+    let name = def.name().to_owned();
+    let src_table = match src.open_multimap_table(def) {
+        Ok(table) => table,
+        Err(redb::TableError::TableDoesNotExist(_)) => return Ok(name),
+        Err(err) => return Err(err.into()),
+    };
+    let mut dst_table = dst.map(|dst| dst.open_multimap_table(def)).transpose()?;
+    for entry in src_table.iter()? {
+        let (k, values) = entry?;
+        for v in values {
+            let v = v?;
+            if let Some(dst_table) = dst_table.as_mut() {
+                dst_table.insert(k.value(), v.value())?;
+            }
+        }
+    }
+    Ok(name)
+}
+
+/// Write a consistent snapshot of `db` into a new database at `path`
+pub fn snapshot(db: &redb::Database, path: &Path) -> anyhow::Result<()> {
+    if path.exists() {
+        bail!("Backup destination {} already exists", path.display());
+    }
+
+    // Write to a temporary file first, so there are never half-written backups
+    let tmp_path = path.with_extension("tmp");
+    let result = write_snapshot(db, &tmp_path).and_then(|()| {
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to move backup into {}", path.display()))
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+fn write_snapshot(db: &redb::Database, path: &Path) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let src = db.begin_read()?;
+    let dst_db = redb::Database::create(path)
+        .with_context(|| format!("Failed to create backup file {}", path.display()))?;
+    let dst = dst_db.begin_write()?;
+    copy_tables(&src, Some(&dst))?;
+    dst.commit()?;
+    drop(dst_db);
+    drop(src);
+    metrics::get().observe_db_transaction(TxKind::Read, "backup", start.elapsed());
+    Ok(())
+}
+
+/// Take backups requested by [`request_snapshot`] while the server runs
+///
+/// The socket is only accessible to the user running the server, who could
+/// read the database anyway.
+pub fn start_socket_thread(db: &Arc<Db>, data_dir: &DataDir) -> anyhow::Result<()> {
+    let path = data_dir.path().join(SOCKET_FILE_NAME);
+    // The data directory is ours, so anything there is left from a previous run
+    match fs::remove_file(&path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            return Err(err)
+                .with_context(|| format!("Failed to remove stale socket {}", path.display()))
+        }
+        _ => {}
+    }
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("Failed to listen on {}", path.display()))?;
+    fs::set_permissions(&path, Permissions::from_mode(0o600))
+        .with_context(|| format!("Failed to set permissions of {}", path.display()))?;
+
+    let db = Arc::downgrade(db);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Some(db) = Weak::upgrade(&db) else {
+                break;
+            };
+            let result = stream.map_err(anyhow::Error::from).and_then(|stream| {
+                let mut reader = BufReader::new(&stream);
+                let mut line = String::new();
+                reader.read_line(&mut line)?;
+                let path = PathBuf::from(line.trim_end_matches('\n'));
+
+                let result = db
+                    .get()
+                    .and_then(|db| snapshot(&db, &path))
+                    .map(|()| info!(path = %path.display(), "Backup complete"));
+                let reply = match &result {
+                    Ok(()) => "ok\n".to_owned(),
+                    Err(err) => format!("{err:#}\n"),
+                };
+                (&stream).write_all(reply.as_bytes())?;
+                result
+            });
+            if let Err(err) = result {
+                warn!(%err, "Requested backup failed");
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Have the server running in `data_dir` write a snapshot to `path`
+pub fn request_snapshot(data_dir: &Path, path: &Path) -> anyhow::Result<()> {
+    // The server may have a different working directory
+    let path = std::env::current_dir()?.join(path);
+    let path = path
+        .to_str()
+        .filter(|path| !path.contains('\n'))
+        .context("Backup path must be valid UTF-8 without newlines")?;
+
+    let socket_path = data_dir.join(SOCKET_FILE_NAME);
+    let mut stream = UnixStream::connect(&socket_path)
+        .with_context(|| format!("Failed to connect to {}", socket_path.display()))?;
+    writeln!(stream, "{path}")?;
+
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    match reply.trim_end() {
+        "ok" => Ok(()),
+        "" => bail!("Server closed the connection without taking a backup"),
+        err => bail!("Server failed to take a backup: {err}"),
+    }
+}
+
+/// Remove temporary files left by backups and restores interrupted by a crash
+pub fn remove_stale_files(data_dir: &DataDir, backup_dir: Option<&Path>) {
+    let mut stale = vec![data_dir.db_path().with_extension("restore-tmp")];
+    if let Some(dir) = backup_dir {
+        stale.extend(
+            fs::read_dir(dir)
+                .into_iter()
+                .flatten()
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| {
+                    let name = entry.file_name();
+                    let name = name.to_string_lossy();
+                    name.starts_with(BACKUP_FILE_PREFIX) && name.ends_with(".tmp")
+                })
+                .map(|entry| entry.path()),
+        );
+    }
+    for path in stale {
+        match fs::remove_file(&path) {
+            Ok(()) => info!(path = %path.display(), "Removed stale temporary file"),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => {
+                warn!(%err, path = %path.display(), "Failed to remove stale temporary file")
+            }
+        }
+    }
+}
+
+/// Check that a backup can be opened, read and used by this build
+fn validate(path: &Path) -> anyhow::Result<()> {
+    let mut magic = [0; REDB_MAGIC.len()];
+    fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .ok()
+        .filter(|_| magic == REDB_MAGIC)
+        .with_context(|| format!("{} is not a redb database", path.display()))?;
+
+    let db = redb::Database::open(path)
+        .with_context(|| format!("{} is not a valid database", path.display()))?;
+
+    let version = migrations::schema_version(&db)?;
+    if version == 0 {
+        bail!("{} is not a backup of this application", path.display());
+    }
+    if migrations::latest_version() < version {
+        bail!(
+            "Backup schema version {version} is newer than the latest one known to this build ({})",
+            migrations::latest_version()
+        );
+    }
+
+    // Reading everything verifies the checksums of all the pages
+    let tx = db.begin_read()?;
+    copy_tables(&tx, None).context("Backup is corrupted")
+}
+
+/// Replace the database in `data_dir` with the backup at `path`
+///
+/// The current database is kept next to it, as `db.redb.before-restore-*`.
+/// Requires exclusive access to the data directory, i.e. the server must not
+/// be running.
+pub fn restore(data_dir: &DataDir, path: &Path) -> anyhow::Result<()> {
+    let db_path = data_dir.db_path();
+    let tmp_path = db_path.with_extension("restore-tmp");
+    fs::copy(path, &tmp_path)
+        .with_context(|| format!("Failed to copy {} into data directory", path.display()))?;
+
+    // Validate the copy, as opening a database may write to it
+    if let Err(err) = validate(&tmp_path) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(err.context(format!("Can't restore from {}", path.display())));
+    }
+
+    if db_path.exists() {
+        let prev_path = db_path.with_extension(format!("redb.before-restore-{}", unix_now()));
+        fs::rename(&db_path, &prev_path)?;
+        info!(path = %prev_path.display(), "Moved previous database away");
+    }
+    fs::rename(&tmp_path, &db_path)?;
+
+    Ok(())
+}
+
+/// Take a backup into `dir` every `interval`, keeping only the `retention`
+/// most recent ones
+pub fn start_periodic_thread(db: &Arc<Db>, dir: PathBuf, interval: Duration, retention: usize) {
+    let db = Arc::downgrade(db);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        let Some(db) = Weak::upgrade(&db) else {
+            break;
+        };
+        // Closed on shutdown
+        let Ok(db) = db.get() else {
+            break;
+        };
+        if let Err(err) = periodic_backup(&db, &dir, retention) {
+            warn!(%err, dir = %dir.display(), "Periodic backup failed");
+        }
+    });
+}
+
+fn periodic_backup(db: &redb::Database, dir: &Path, retention: usize) -> anyhow::Result<()> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!(
+        "{BACKUP_FILE_PREFIX}{}{BACKUP_FILE_SUFFIX}",
+        unix_now()
+    ));
+    snapshot(db, &path)?;
+    info!(path = %path.display(), "Backup complete");
+
+    // Timestamps in names sort chronologically
+    let mut backups = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| name.starts_with(BACKUP_FILE_PREFIX) && name.ends_with(BACKUP_FILE_SUFFIX))
+        .collect::<Vec<_>>();
+    backups.sort();
+    let excess = backups.len().saturating_sub(retention);
+    for name in &backups[..excess] {
+        fs::remove_file(dir.join(name))?;
+        info!(name, "Removed old backup");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn backup_restore_test() {
+    let dir = std::env::temp_dir().join(format!("htmx-demo-backup-test-{}", std::process::id()));
+    let data_dir = DataDir::open(&dir.join("data")).unwrap();
+    let db = data_dir.open_db().unwrap();
+    migrations::run(&db, false).unwrap();
+
+    let backup_path = dir.join("backup.redb");
+    snapshot(&db, &backup_path).unwrap();
+    assert!(snapshot(&db, &backup_path).is_err());
+    drop(db);
+
+    restore(&data_dir, &backup_path).unwrap();
+    let db = data_dir.open_db().unwrap();
+    assert_eq!(
+        migrations::schema_version(&db).unwrap(),
+        migrations::latest_version()
+    );
+
+    let garbage_path = dir.join("garbage.redb");
+    fs::write(&garbage_path, b"not a database").unwrap();
+    assert!(restore(&data_dir, &garbage_path).is_err());
+    assert_eq!(fs::read(&garbage_path).unwrap(), b"not a database");
+
+    drop(db);
+    drop(data_dir);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn request_snapshot_test() {
+    let dir = std::env::temp_dir().join(format!(
+        "htmx-demo-backup-socket-test-{}",
+        std::process::id()
+    ));
+    let data_dir = DataDir::open(&dir.join("data")).unwrap();
+    let db = data_dir.open_db().unwrap();
+    migrations::run(&db, false).unwrap();
+    let db = Arc::new(Db::new(db));
+    start_socket_thread(&db, &data_dir).unwrap();
+
+    let backup_path = dir.join("backup.redb");
+    request_snapshot(data_dir.path(), &backup_path).unwrap();
+    validate(&backup_path).unwrap();
+    let err = request_snapshot(data_dir.path(), &backup_path).unwrap_err();
+    assert!(err.to_string().contains("already exists"), "{err}");
+
+    // Left by a crash
+    let backup_dir = dir.join("backups");
+    fs::create_dir_all(&backup_dir).unwrap();
+    let tmp_path = backup_dir.join(format!("{BACKUP_FILE_PREFIX}1.tmp"));
+    fs::write(&tmp_path, "").unwrap();
+    remove_stale_files(&data_dir, Some(&backup_dir));
+    assert!(!tmp_path.exists());
+
+    drop(db);
+    drop(data_dir);
+    fs::remove_dir_all(&dir).unwrap();
+}
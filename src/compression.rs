@@ -0,0 +1,200 @@
+//! Response compression negotiated via `Accept-Encoding`
+//!
+//! Embedded assets come with brotli and gzip variants precompressed in
+//! `build.rs`; rendered HTML is compressed on the fly.
+
+use std::io::Write;
+
+use hyper::http::HeaderValue;
+use hyper::{header, HeaderMap, StatusCode};
+use tracing::warn;
+
+/// Don't bother compressing HTML smaller than this
+const HTML_COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Faster than the build-time quality, still much better than gzip
+const BROTLI_DYNAMIC_QUALITY: u32 = 5;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// Encodings the client accepts, parsed from `Accept-Encoding`
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct AcceptEncoding {
+    brotli: bool,
+    gzip: bool,
+}
+
+impl AcceptEncoding {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let mut accept = Self::default();
+        let mut wildcard = None;
+        let (mut brotli, mut gzip) = (None, None);
+
+        for item in headers
+            .get_all(header::ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+        {
+            let mut parts = item.split(';').map(str::trim);
+            let coding = parts.next().unwrap_or_default();
+            let acceptable = parts
+                .filter_map(|p| p.strip_prefix("q="))
+                .filter_map(|q| q.parse::<f32>().ok())
+                .all(|q| 0.0 < q);
+
+            match coding {
+                "br" => brotli = Some(acceptable),
+                "gzip" => gzip = Some(acceptable),
+                "*" => wildcard = Some(acceptable),
+                _ => {}
+            }
+        }
+
+        accept.brotli = brotli.or(wildcard).unwrap_or(false);
+        accept.gzip = gzip.or(wildcard).unwrap_or(false);
+        accept
+    }
+
+    /// Pick the best encoding out of the ones available for a resource
+    pub fn preferred(self, brotli_available: bool, gzip_available: bool) -> Option<Encoding> {
+        if self.brotli && brotli_available {
+            Some(Encoding::Brotli)
+        } else if self.gzip && gzip_available {
+            Some(Encoding::Gzip)
+        } else {
+            None
+        }
+    }
+}
+
+pub fn compress(encoding: Encoding, data: &[u8]) -> Vec<u8> {
+    match encoding {
+        Encoding::Brotli => {
+            let mut out = vec![];
+            {
+                let mut writer =
+                    brotli::CompressorWriter::new(&mut out, 4096, BROTLI_DYNAMIC_QUALITY, 22);
+                writer.write_all(data).expect("can't fail");
+            }
+            out
+        }
+        Encoding::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+            encoder.write_all(data).expect("can't fail");
+            encoder.finish().expect("can't fail")
+        }
+    }
+}
+
+/// Compress an HTML response on the fly, if the client supports it and it's
+/// big enough to be worth it
+pub fn compress_html_response(resp: astra::Response, accept: AcceptEncoding) -> astra::Response {
+    let is_html = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/html"));
+    if !is_html
+        || resp.headers().contains_key(header::CONTENT_ENCODING)
+        || resp.status() == StatusCode::NOT_MODIFIED
+    {
+        return resp;
+    }
+
+    let (mut parts, body) = resp.into_parts();
+    parts
+        .headers
+        .append(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+
+    let mut content = vec![];
+    for chunk in body {
+        match chunk {
+            Ok(chunk) => content.extend_from_slice(&chunk),
+            Err(err) => {
+                warn!(%err, "Failed to read html response body for compression");
+                parts.status = StatusCode::INTERNAL_SERVER_ERROR;
+                return astra::Response::from_parts(parts, astra::Body::empty());
+            }
+        }
+    }
+
+    let encoding = accept.preferred(true, true);
+    let content = match encoding {
+        Some(encoding) if HTML_COMPRESSION_THRESHOLD <= content.len() => {
+            parts.headers.insert(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_static(encoding.as_str()),
+            );
+            // A different representation needs a different ETag
+            if let Some(etag) = parts
+                .headers
+                .get(header::ETAG)
+                .and_then(|v| v.to_str().ok())
+            {
+                let etag = with_encoding_suffix(etag, encoding);
+                parts.headers.insert(
+                    header::ETAG,
+                    HeaderValue::from_str(&etag).expect("can't fail"),
+                );
+            }
+            compress(encoding, &content)
+        }
+        _ => content,
+    };
+    parts
+        .headers
+        .insert(header::CONTENT_LENGTH, HeaderValue::from(content.len()));
+
+    astra::Response::from_parts(parts, astra::Body::new(content))
+}
+
+/// `"tag"` -> `"tag-br"`, keeping the weak prefix if any
+pub fn with_encoding_suffix(etag: &str, encoding: Encoding) -> String {
+    match etag.strip_suffix('"') {
+        Some(etag) => format!("{etag}-{}\"", encoding.as_str()),
+        None => etag.to_owned(),
+    }
+}
+
+#[test]
+fn accept_encoding_test() {
+    let parse = |value| {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static(value));
+        AcceptEncoding::from_headers(&headers)
+    };
+
+    assert_eq!(
+        parse("gzip, deflate, br").preferred(true, true),
+        Some(Encoding::Brotli)
+    );
+    assert_eq!(
+        parse("gzip, deflate, br").preferred(false, true),
+        Some(Encoding::Gzip)
+    );
+    assert_eq!(
+        parse("br;q=0, gzip;q=0.5").preferred(true, true),
+        Some(Encoding::Gzip)
+    );
+    assert_eq!(parse("*").preferred(true, true), Some(Encoding::Brotli));
+    assert_eq!(
+        parse("*, br;q=0").preferred(true, true),
+        Some(Encoding::Gzip)
+    );
+    assert_eq!(parse("identity").preferred(true, true), None);
+    assert_eq!(AcceptEncoding::default().preferred(true, true), None);
+}
@@ -0,0 +1,163 @@
+//! Conditional GET support (`ETag`, `If-None-Match`, `Last-Modified`,
+//! `If-Modified-Since`)
+
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use hyper::{header, HeaderMap, Method};
+use sha2::{Digest, Sha256};
+
+/// An entity tag, as sent in the `ETag` header
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ETag {
+    weak: bool,
+    tag: String,
+}
+
+impl ETag {
+    /// For byte-for-byte identical content, like embedded assets
+    pub fn strong(tag: &str) -> Self {
+        Self {
+            weak: false,
+            tag: tag.to_owned(),
+        }
+    }
+
+    /// Weak comparison, as required for `If-None-Match`
+    ///
+    /// Compressed representations have their own tags, with a content-encoding
+    /// suffix (see [`crate::compression`]), so a cached copy only matches the
+    /// representation it was.
+    fn matches_weak(&self, other: &str) -> bool {
+        let other = other.strip_prefix("W/").unwrap_or(other);
+        other.strip_prefix('"').and_then(|o| o.strip_suffix('"')) == Some(self.tag.as_str())
+    }
+}
+
+impl fmt::Display for ETag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.weak {
+            f.write_str("W/")?;
+        }
+        write!(f, "\"{}\"", self.tag)
+    }
+}
+
+/// Shortened hex-encoded sha256, the same as used for embedded assets
+pub fn short_hash(content: &[u8]) -> String {
+    Sha256::digest(content)[..8]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+pub fn unix_secs_to_system_time(secs: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// Whether the client's cached copy, described by the conditional headers in
+/// `req_headers`, is still fresh given the validators in `resp_headers`
+pub fn is_not_modified(method: &Method, req_headers: &HeaderMap, resp_headers: &HeaderMap) -> bool {
+    if method != Method::GET && method != Method::HEAD {
+        return false;
+    }
+
+    // `If-None-Match` takes precedence over `If-Modified-Since` when present
+    if req_headers.contains_key(header::IF_NONE_MATCH) {
+        let Some(etag) = resp_headers
+            .get(header::ETAG)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_etag)
+        else {
+            return false;
+        };
+
+        return req_headers
+            .get_all(header::IF_NONE_MATCH)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .any(|candidate| candidate == "*" || etag.matches_weak(candidate));
+    }
+
+    let if_modified_since = req_headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok());
+    let last_modified = resp_headers
+        .get(header::LAST_MODIFIED)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok());
+
+    match (if_modified_since, last_modified) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+fn parse_etag(s: &str) -> Option<ETag> {
+    let (weak, rest) = match s.strip_prefix("W/") {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let tag = rest.strip_prefix('"')?.strip_suffix('"')?;
+    Some(ETag {
+        weak,
+        tag: tag.to_owned(),
+    })
+}
+
+#[test]
+fn is_not_modified_test() {
+    use hyper::http::HeaderValue;
+
+    let mut resp = HeaderMap::new();
+    resp.insert(header::ETAG, HeaderValue::from_static("\"abc\""));
+    resp.insert(
+        header::LAST_MODIFIED,
+        HeaderValue::from_static("Sun, 06 Nov 1994 08:49:37 GMT"),
+    );
+
+    let req = |name, value| {
+        let mut req = HeaderMap::new();
+        req.insert(name, HeaderValue::from_static(value));
+        req
+    };
+
+    assert!(is_not_modified(
+        &Method::GET,
+        &req(header::IF_NONE_MATCH, "\"xyz\", W/\"abc\""),
+        &resp
+    ));
+    assert!(is_not_modified(
+        &Method::GET,
+        &req(header::IF_NONE_MATCH, "*"),
+        &resp
+    ));
+    assert!(!is_not_modified(
+        &Method::GET,
+        &req(header::IF_NONE_MATCH, "\"xyz\""),
+        &resp
+    ));
+    assert!(!is_not_modified(
+        &Method::GET,
+        &req(header::IF_NONE_MATCH, "W/\"abc-br\""),
+        &resp
+    ));
+    assert!(!is_not_modified(
+        &Method::POST,
+        &req(header::IF_NONE_MATCH, "\"abc\""),
+        &resp
+    ));
+    assert!(is_not_modified(
+        &Method::GET,
+        &req(header::IF_MODIFIED_SINCE, "Sun, 06 Nov 1994 08:49:37 GMT"),
+        &resp
+    ));
+    assert!(!is_not_modified(
+        &Method::GET,
+        &req(header::IF_MODIFIED_SINCE, "Sat, 05 Nov 1994 08:49:37 GMT"),
+        &resp
+    ));
+}
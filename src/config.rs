@@ -0,0 +1,670 @@
+//! Application configuration
+//!
+//! Layered, from lowest to highest priority: built-in defaults, TOML config
+//! file (`--config`), environment variables and command line arguments (see
+//! [`crate::opts`]).
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{fmt, fs};
+
+use anyhow::{bail, Context};
+use clap::ValueEnum;
+use hyper::http::{HeaderName, HeaderValue};
+use lettre::Address;
+use serde::{Deserialize, Serialize};
+
+use crate::opts::ConfigOverrides;
+use crate::rate_limit::pre;
+use crate::security_headers;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// `host:port`, `unix:<path>`, or `systemd` for a socket passed in by
+    /// systemd socket activation
+    pub listen: String,
+    /// Permissions of a `unix:` socket, in octal (e.g. `660`)
+    pub socket_mode: Option<String>,
+    /// Header the proxy in front of a unix socket puts the client's IP in,
+    /// e.g. `X-Real-IP`, or `X-Forwarded-For` (its last entry); required
+    /// there, as there's no peer address to rate limit by
+    pub client_ip_header: Option<String>,
+    /// Serve HTTPS on `listen`, plain HTTP if not set
+    pub tls: Option<TlsConfig>,
+    pub dev: bool,
+    pub log: LogConfig,
+    /// Where the database and other persistent files are kept
+    pub data_dir: PathBuf,
+    /// How long to wait for in-flight requests on shutdown
+    pub shutdown_timeout_secs: u64,
+    pub rate_limit: RateLimitConfig,
+    pub smtp: Option<SmtpConfig>,
+    /// Periodic online backups, disabled if not set
+    pub backup: Option<BackupConfig>,
+    pub metrics: MetricsConfig,
+    pub security_headers: SecurityHeadersConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: "localhost:3000".into(),
+            socket_mode: None,
+            client_ip_header: None,
+            tls: None,
+            dev: false,
+            log: Default::default(),
+            data_dir: "./data".into(),
+            shutdown_timeout_secs: 30,
+            rate_limit: Default::default(),
+            smtp: None,
+            backup: None,
+            metrics: Default::default(),
+            security_headers: Default::default(),
+        }
+    }
+}
+
+/// Where to listen, parsed from [`Config::listen`]
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ListenAddr {
+    Tcp(String),
+    Unix(PathBuf),
+    Systemd,
+}
+
+impl ListenAddr {
+    pub fn parse(listen: &str) -> Option<Self> {
+        if listen == "systemd" {
+            return Some(ListenAddr::Systemd);
+        }
+        if let Some(path) = listen.strip_prefix("unix:") {
+            return (!path.is_empty()).then(|| ListenAddr::Unix(path.into()));
+        }
+        listen
+            .to_socket_addrs()
+            .is_ok()
+            .then(|| ListenAddr::Tcp(listen.to_owned()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain; re-read along with `key` on SIGHUP
+    pub cert: PathBuf,
+    /// PEM private key
+    pub key: PathBuf,
+    /// Plain HTTP listener redirecting to HTTPS, e.g. `0.0.0.0:80`
+    pub redirect_listen: Option<String>,
+    /// `max-age` of the `Strict-Transport-Security` header; 0 disables it
+    pub hsts_max_age_secs: u64,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert: PathBuf::new(),
+            key: PathBuf::new(),
+            redirect_listen: None,
+            hsts_max_age_secs: 365 * 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    /// Log into this file instead of stderr, rotated according to `rotation`
+    pub file: Option<PathBuf>,
+    pub rotation: LogRotation,
+    /// How many rotated log files to keep; all of them if not set
+    pub max_files: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable, one line per event
+    #[default]
+    Full,
+    /// Like `full`, but shorter
+    Compact,
+    /// Human readable, multi-line
+    Pretty,
+    /// Newline-delimited JSON, for log aggregators
+    Json,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Fast, imprecise limiter checked first
+    pub pre: LimitConfig,
+    /// Precise limiter, consulted only when `pre` is exceeded
+    pub conventional: LimitConfig,
+    /// Login attempts per account, whatever IPs they come from
+    pub login: LimitConfig,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            pre: LimitConfig {
+                threshold: 20,
+                window_secs: 60,
+            },
+            conventional: LimitConfig {
+                threshold: 10,
+                window_secs: 60,
+            },
+            login: LimitConfig {
+                threshold: 5,
+                window_secs: 300,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct LimitConfig {
+    /// Requests allowed per peer IP (or account, for `login`) within a window
+    pub threshold: usize,
+    pub window_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpConfig {
+    pub hostname: String,
+    pub port: u16,
+    pub user: String,
+    pub password: Secret,
+    pub from: String,
+    pub to: String,
+}
+
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        Self {
+            hostname: String::new(),
+            port: 587,
+            user: String::new(),
+            password: Secret(String::new()),
+            from: String::new(),
+            to: String::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackupConfig {
+    pub dir: PathBuf,
+    pub interval_secs: u64,
+    /// How many most recent backups to keep
+    pub retention: usize,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::new(),
+            interval_secs: 60 * 60,
+            retention: 24,
+        }
+    }
+}
+
+/// Access to `/metrics`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Peers allowed to scrape without a token
+    pub allowed_ips: Vec<IpAddr>,
+    /// Grants access from anywhere, as `Authorization: Bearer <token>`
+    pub token: Option<Secret>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_ips: vec![
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ],
+            token: None,
+        }
+    }
+}
+
+/// Headers set on every response; empty values are not sent
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityHeadersConfig {
+    pub enabled: bool,
+    /// `{nonce}` is replaced with a fresh nonce on every request
+    pub content_security_policy: String,
+    pub referrer_policy: String,
+    pub permissions_policy: String,
+    pub frame_options: String,
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            content_security_policy: "default-src 'self'; script-src 'self' 'nonce-{nonce}'; \
+                style-src 'self'; img-src 'self' data:; object-src 'none'; base-uri 'none'; \
+                form-action 'self'; frame-ancestors 'none'"
+                .into(),
+            referrer_policy: "strict-origin-when-cross-origin".into(),
+            permissions_policy: "camera=(), geolocation=(), microphone=(), payment=()".into(),
+            frame_options: "DENY".into(),
+        }
+    }
+}
+
+/// A value that is never printed or logged
+#[derive(Deserialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str("<redacted>")
+    }
+}
+
+impl Config {
+    pub fn listen_addr(&self) -> ListenAddr {
+        ListenAddr::parse(&self.listen).expect("validated")
+    }
+
+    pub fn client_ip_header(&self) -> Option<HeaderName> {
+        self.client_ip_header
+            .as_deref()
+            .map(|name| HeaderName::from_bytes(name.as_bytes()).expect("validated"))
+    }
+
+    pub fn socket_mode(&self) -> Option<u32> {
+        self.socket_mode
+            .as_deref()
+            .map(|mode| u32::from_str_radix(mode, 8).expect("validated"))
+    }
+
+    pub fn load(path: Option<&Path>, overrides: &ConfigOverrides) -> anyhow::Result<Self> {
+        let mut config = match path {
+            Some(path) => {
+                let content = fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read config file {}", path.display()))?;
+                toml::from_str(&content)
+                    .with_context(|| format!("Invalid config file {}", path.display()))?
+            }
+            None => Config::default(),
+        };
+
+        config.apply(overrides);
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn apply(&mut self, overrides: &ConfigOverrides) {
+        if let Some(listen) = &overrides.listen {
+            self.listen = listen.clone();
+        }
+        if let Some(v) = &overrides.socket_mode {
+            self.socket_mode = Some(v.clone());
+        }
+        if let Some(v) = &overrides.client_ip_header {
+            self.client_ip_header = Some(v.clone());
+        }
+        if overrides.dev {
+            self.dev = true;
+        }
+        let any_tls_override = overrides.tls_cert.is_some()
+            || overrides.tls_key.is_some()
+            || overrides.tls_redirect_listen.is_some()
+            || overrides.hsts_max_age_secs.is_some();
+        if any_tls_override {
+            let tls = self.tls.get_or_insert_with(TlsConfig::default);
+            if let Some(v) = &overrides.tls_cert {
+                tls.cert = v.clone();
+            }
+            if let Some(v) = &overrides.tls_key {
+                tls.key = v.clone();
+            }
+            if let Some(v) = &overrides.tls_redirect_listen {
+                tls.redirect_listen = Some(v.clone());
+            }
+            if let Some(v) = overrides.hsts_max_age_secs {
+                tls.hsts_max_age_secs = v;
+            }
+        }
+        if let Some(v) = overrides.log_format {
+            self.log.format = v;
+        }
+        if let Some(v) = &overrides.log_file {
+            self.log.file = Some(v.clone());
+        }
+        if let Some(v) = overrides.log_rotation {
+            self.log.rotation = v;
+        }
+        if let Some(v) = overrides.log_max_files {
+            self.log.max_files = Some(v);
+        }
+        if let Some(data_dir) = &overrides.data_dir {
+            self.data_dir = data_dir.clone();
+        }
+        if let Some(v) = overrides.shutdown_timeout_secs {
+            self.shutdown_timeout_secs = v;
+        }
+
+        let limits = &mut self.rate_limit;
+        if let Some(v) = overrides.pre_rate_limit_threshold {
+            limits.pre.threshold = v;
+        }
+        if let Some(v) = overrides.pre_rate_limit_window_secs {
+            limits.pre.window_secs = v;
+        }
+        if let Some(v) = overrides.rate_limit_threshold {
+            limits.conventional.threshold = v;
+        }
+        if let Some(v) = overrides.rate_limit_window_secs {
+            limits.conventional.window_secs = v;
+        }
+
+        let o = overrides;
+        let any_smtp_override = o.smtp_hostname.is_some()
+            || o.smtp_port.is_some()
+            || o.smtp_user.is_some()
+            || o.smtp_password.is_some()
+            || o.smtp_from.is_some()
+            || o.smtp_to.is_some();
+        if any_smtp_override {
+            let smtp = self.smtp.get_or_insert_with(SmtpConfig::default);
+            if let Some(v) = &o.smtp_hostname {
+                smtp.hostname = v.clone();
+            }
+            if let Some(v) = o.smtp_port {
+                smtp.port = v;
+            }
+            if let Some(v) = &o.smtp_user {
+                smtp.user = v.clone();
+            }
+            if let Some(v) = &o.smtp_password {
+                smtp.password = Secret(v.clone());
+            }
+            if let Some(v) = &o.smtp_from {
+                smtp.from = v.clone();
+            }
+            if let Some(v) = &o.smtp_to {
+                smtp.to = v.clone();
+            }
+        }
+
+        let any_backup_override = o.backup_dir.is_some()
+            || o.backup_interval_secs.is_some()
+            || o.backup_retention.is_some();
+        if any_backup_override {
+            let backup = self.backup.get_or_insert_with(BackupConfig::default);
+            if let Some(v) = &o.backup_dir {
+                backup.dir = v.clone();
+            }
+            if let Some(v) = o.backup_interval_secs {
+                backup.interval_secs = v;
+            }
+            if let Some(v) = o.backup_retention {
+                backup.retention = v;
+            }
+        }
+
+        if let Some(v) = &o.metrics_allowed_ips {
+            self.metrics.allowed_ips = v.clone();
+        }
+        if let Some(v) = &o.metrics_token {
+            self.metrics.token = Some(Secret(v.clone()));
+        }
+    }
+
+    /// Check everything at once, so all problems get reported together
+    fn validate(&self) -> anyhow::Result<()> {
+        let mut errors = vec![];
+
+        if ListenAddr::parse(&self.listen).is_none() {
+            errors.push(format!(
+                "listen: `{}` is neither a `host:port` address, `unix:<path>` nor `systemd`",
+                self.listen
+            ));
+        }
+        if let Some(mode) = &self.socket_mode {
+            if !u32::from_str_radix(mode, 8).is_ok_and(|mode| mode <= 0o777) {
+                errors.push(format!(
+                    "socket_mode: `{mode}` is not an octal mode like `660`"
+                ));
+            }
+        }
+        if let Some(name) = &self.client_ip_header {
+            if HeaderName::from_bytes(name.as_bytes()).is_err() {
+                errors.push(format!("client_ip_header: `{name}` is not a header name"));
+            }
+        }
+
+        if let Some(tls) = &self.tls {
+            for (name, path) in [("cert", &tls.cert), ("key", &tls.key)] {
+                if path.as_os_str().is_empty() {
+                    errors.push(format!("tls.{name}: must be set when tls is configured"));
+                }
+            }
+            if let Some(redirect_listen) = &tls.redirect_listen {
+                if redirect_listen.to_socket_addrs().is_err() {
+                    errors.push(format!(
+                        "tls.redirect_listen: `{redirect_listen}` is not a valid `host:port` address"
+                    ));
+                }
+            }
+        }
+
+        if let Some(file) = &self.log.file {
+            if file.file_name().is_none() {
+                errors.push(format!("log.file: {} is not a file path", file.display()));
+            }
+        }
+        if self.log.max_files == Some(0) {
+            errors.push("log.max_files: must be greater than 0".to_owned());
+        }
+
+        if self.data_dir.as_os_str().is_empty() {
+            errors.push("data_dir: must not be empty".to_owned());
+        } else if self.data_dir.is_file() {
+            errors.push(format!(
+                "data_dir: {} is a file, not a directory",
+                self.data_dir.display()
+            ));
+        }
+
+        for (name, limit) in [
+            ("rate_limit.pre", &self.rate_limit.pre),
+            ("rate_limit.conventional", &self.rate_limit.conventional),
+            ("rate_limit.login", &self.rate_limit.login),
+        ] {
+            if limit.threshold == 0 {
+                errors.push(format!("{name}.threshold: must be greater than 0"));
+            }
+            if limit.window_secs == 0 {
+                errors.push(format!("{name}.window_secs: must be greater than 0"));
+            }
+        }
+        if pre::FastPreRateLimiter::MAX_THRESHOLD < self.rate_limit.pre.threshold {
+            errors.push(format!(
+                "rate_limit.pre.threshold: must be at most {}",
+                pre::FastPreRateLimiter::MAX_THRESHOLD
+            ));
+        }
+
+        if let Some(smtp) = &self.smtp {
+            for (name, value) in [
+                ("hostname", &smtp.hostname),
+                ("user", &smtp.user),
+                ("password", &smtp.password.0),
+            ] {
+                if value.is_empty() {
+                    errors.push(format!("smtp.{name}: must be set when smtp is configured"));
+                }
+            }
+            for (name, value) in [("from", &smtp.from), ("to", &smtp.to)] {
+                if Address::from_str(value).is_err() {
+                    errors.push(format!(
+                        "smtp.{name}: `{value}` is not a valid email address"
+                    ));
+                }
+            }
+            if smtp.port == 0 {
+                errors.push("smtp.port: must not be 0".to_owned());
+            }
+        }
+
+        if let Some(backup) = &self.backup {
+            if backup.dir.as_os_str().is_empty() {
+                errors.push("backup.dir: must be set when backups are configured".to_owned());
+            }
+            if backup.interval_secs == 0 {
+                errors.push("backup.interval_secs: must be greater than 0".to_owned());
+            }
+            if backup.retention == 0 {
+                errors.push("backup.retention: must be greater than 0".to_owned());
+            }
+        }
+
+        if self
+            .metrics
+            .token
+            .as_ref()
+            .is_some_and(|token| token.0.is_empty())
+        {
+            errors.push("metrics.token: must not be empty".to_owned());
+        }
+
+        let headers = &self.security_headers;
+        for (name, value) in [
+            (
+                "content_security_policy",
+                &headers
+                    .content_security_policy
+                    .replace(security_headers::NONCE_PLACEHOLDER, "nonce"),
+            ),
+            ("referrer_policy", &headers.referrer_policy),
+            ("permissions_policy", &headers.permissions_policy),
+            ("frame_options", &headers.frame_options),
+        ] {
+            if HeaderValue::from_str(value).is_err() {
+                errors.push(format!("security_headers.{name}: not a valid header value"));
+            }
+        }
+
+        if !errors.is_empty() {
+            bail!("Invalid configuration:\n  - {}", errors.join("\n  - "));
+        }
+
+        Ok(())
+    }
+
+    /// The effective config as TOML, with secrets redacted
+    pub fn to_toml_redacted(&self) -> anyhow::Result<String> {
+        toml::to_string_pretty(self).context("Failed to serialize config")
+    }
+}
+
+#[test]
+fn config_load_test() {
+    let config: Config = toml::from_str(
+        r#"
+        listen = "127.0.0.1:8080"
+
+        [rate_limit.pre]
+        threshold = 100
+        window_secs = 30
+
+        [smtp]
+        hostname = "smtp.example.com"
+        user = "user"
+        password = "hunter2"
+        from = "from@example.com"
+        to = "to@example.com"
+        "#,
+    )
+    .unwrap();
+
+    config.validate().unwrap();
+    assert_eq!(
+        config.listen_addr(),
+        ListenAddr::Tcp("127.0.0.1:8080".to_owned())
+    );
+    assert_eq!(config.rate_limit.pre.threshold, 100);
+    assert_eq!(config.rate_limit.conventional.threshold, 10);
+
+    let printed = config.to_toml_redacted().unwrap();
+    assert!(!printed.contains("hunter2"));
+    assert!(printed.contains("<redacted>"));
+
+    assert_eq!(
+        ListenAddr::parse("unix:/run/htmx-demo.sock"),
+        Some(ListenAddr::Unix("/run/htmx-demo.sock".into()))
+    );
+    assert_eq!(ListenAddr::parse("systemd"), Some(ListenAddr::Systemd));
+    assert_eq!(ListenAddr::parse("unix:"), None);
+
+    let invalid: Config = toml::from_str(
+        r#"
+        socket_mode = "999"
+        client_ip_header = "X Real IP"
+
+        [rate_limit.conventional]
+        threshold = 0
+        window_secs = 60
+
+        [smtp]
+        to = "not an email"
+
+        [tls]
+        cert = "cert.pem"
+        redirect_listen = "nowhere"
+        "#,
+    )
+    .unwrap();
+    let err = invalid.validate().unwrap_err().to_string();
+    assert!(err.contains("rate_limit.conventional.threshold"));
+    assert!(err.contains("smtp.hostname"));
+    assert!(err.contains("smtp.to"));
+    assert!(err.contains("socket_mode"));
+    assert!(err.contains("client_ip_header"));
+    assert!(err.contains("tls.key"));
+    assert!(!err.contains("tls.cert"));
+    assert!(err.contains("tls.redirect_listen"));
+}
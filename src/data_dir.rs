@@ -0,0 +1,141 @@
+//! The data directory, holding the database and anything else persistent
+//!
+//! An exclusive lock on `<data_dir>/lock` makes sure only one instance uses
+//! it at a time.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use fs2::FileExt;
+use redb::{DatabaseError, StorageError};
+use tracing::{info, warn};
+
+/// Where the database lived before the data directory was configurable
+const LEGACY_DB_PATH: &str = "./target/db.redb";
+
+pub struct DataDir {
+    path: PathBuf,
+    // Held (locked) for as long as the `DataDir` is alive
+    _lock: File,
+}
+
+impl DataDir {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        fs::create_dir_all(path)
+            .with_context(|| format!("Failed to create data directory {}", path.display()))?;
+
+        let lock_path = path.join("lock");
+        let mut lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&lock_path)
+            .with_context(|| format!("Failed to open lock file {}", lock_path.display()))?;
+
+        if lock.try_lock_exclusive().is_err() {
+            let holder = fs::read_to_string(&lock_path).unwrap_or_default();
+            bail!(
+                "Data directory {} is already in use by another instance (pid: {}). Stop it first or use a different `--data-dir`",
+                path.display(),
+                if holder.trim().is_empty() { "unknown" } else { holder.trim() }
+            );
+        }
+
+        // Just informative, for the error message above
+        lock.set_len(0)?;
+        write!(lock, "{}", std::process::id())?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            _lock: lock,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn db_path(&self) -> PathBuf {
+        self.path.join("db.redb")
+    }
+
+    /// Open (or create) the database, explaining what to do if that fails
+    pub fn open_db(&self) -> anyhow::Result<redb::Database> {
+        let db_path = self.db_path();
+        migrate_legacy_db(Path::new(LEGACY_DB_PATH), &db_path);
+        let path = db_path.display();
+
+        redb::Database::create(&db_path).map_err(|err| match err {
+            DatabaseError::DatabaseAlreadyOpen => {
+                anyhow::anyhow!("Database {path} is already open by another process")
+            }
+            DatabaseError::UpgradeRequired(version) => anyhow::anyhow!(
+                "Database {path} uses an incompatible file format (version {version}) and must be upgraded before use, or restored from a backup"
+            ),
+            DatabaseError::Storage(StorageError::Corrupted(msg)) => anyhow::anyhow!(
+                "Database {path} is corrupted ({msg}); restore it from a backup, or move it away to start with an empty one"
+            ),
+            err => anyhow::Error::new(err).context(format!("Failed to open database {path}")),
+        })
+    }
+}
+
+/// Move a database left at the old hardcoded location into the data directory,
+/// unless there already is one there
+fn migrate_legacy_db(legacy: &Path, db_path: &Path) {
+    if !legacy.is_file() || db_path.exists() {
+        return;
+    }
+
+    match fs::rename(legacy, db_path) {
+        Ok(()) => info!(
+            from = %legacy.display(),
+            to = %db_path.display(),
+            "Moved database from its old location into the data directory"
+        ),
+        Err(err) => warn!(
+            from = %legacy.display(),
+            to = %db_path.display(),
+            %err,
+            "Found a database at the old location but failed to move it; starting with an empty one. Stop the server and move it by hand to keep its data"
+        ),
+    }
+}
+
+#[test]
+fn data_dir_lock_test() {
+    let path = std::env::temp_dir().join(format!("htmx-demo-data-dir-test-{}", std::process::id()));
+
+    let data_dir = DataDir::open(&path).unwrap();
+    let err = DataDir::open(&path).err().unwrap().to_string();
+    assert!(err.contains("already in use"), "{err}");
+
+    drop(data_dir);
+    DataDir::open(&path).unwrap();
+
+    fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn migrate_legacy_db_test() {
+    let path =
+        std::env::temp_dir().join(format!("htmx-demo-legacy-db-test-{}", std::process::id()));
+    fs::create_dir_all(&path).unwrap();
+    let legacy = path.join("legacy.redb");
+    let db_path = path.join("db.redb");
+
+    fs::write(&legacy, "old").unwrap();
+    migrate_legacy_db(&legacy, &db_path);
+    assert!(!legacy.exists());
+    assert_eq!(fs::read_to_string(&db_path).unwrap(), "old");
+
+    // An existing database is never overwritten
+    fs::write(&legacy, "older").unwrap();
+    migrate_legacy_db(&legacy, &db_path);
+    assert_eq!(fs::read_to_string(&db_path).unwrap(), "old");
+
+    fs::remove_dir_all(&path).unwrap();
+}
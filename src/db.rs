@@ -0,0 +1,41 @@
+//! Shared handle to the database
+//!
+//! Request handlers and background threads all hold an `Arc<Db>`, so the
+//! database itself sits behind a lock, to be closed cleanly on shutdown no
+//! matter who still has a handle.
+
+use std::ops::Deref;
+use std::sync::{RwLock, RwLockReadGuard};
+
+use anyhow::bail;
+
+pub struct Db(RwLock<Option<redb::Database>>);
+
+impl Db {
+    pub fn new(db: redb::Database) -> Self {
+        Self(RwLock::new(Some(db)))
+    }
+
+    pub fn get(&self) -> anyhow::Result<DbRef<'_>> {
+        let guard = self.0.read().expect("locking failed");
+        if guard.is_none() {
+            bail!("Database is closed");
+        }
+        Ok(DbRef(guard))
+    }
+
+    /// Wait for everyone using the database to finish, and close it
+    pub fn close(&self) {
+        drop(self.0.write().expect("locking failed").take());
+    }
+}
+
+pub struct DbRef<'a>(RwLockReadGuard<'a, Option<redb::Database>>);
+
+impl Deref for DbRef<'_> {
+    type Target = redb::Database;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref().expect("checked in Db::get")
+    }
+}
@@ -0,0 +1,186 @@
+//! Development mode (`--dev`)
+//!
+//! Static files are served straight from `static/` instead of the copies
+//! embedded at build time, and pages get a small script that listens on
+//! `/dev/reload` (Server-Sent Events) and reloads the page when static files
+//! change or the server gets restarted with a new binary.
+
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+use std::{fs, thread};
+
+use tracing::{debug, info};
+
+pub const STATIC_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/static");
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static WATCHER: OnceLock<Watcher> = OnceLock::new();
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+    let watcher = WATCHER.get_or_init(Watcher::default);
+    watcher.start_poll_thread();
+    info!(dir = STATIC_DIR, "Dev mode: serving static files from disk");
+}
+
+/// Read a static file from disk, refusing anything that could escape
+/// [`STATIC_DIR`]
+pub fn read_static(path: &str) -> Option<Vec<u8>> {
+    let path = Path::new(path);
+    if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return None;
+    }
+    fs::read(Path::new(STATIC_DIR).join(path)).ok()
+}
+
+/// Script injected into every page, reloading it on changes
+pub const RELOAD_SCRIPT: &str = r#"(() => {
+  let instance = null;
+  const source = new EventSource("/dev/reload");
+  source.addEventListener("instance", (e) => {
+    if (instance !== null && instance !== e.data) {
+      location.reload();
+    }
+    instance = e.data;
+  });
+  source.addEventListener("reload", () => location.reload());
+})();"#;
+
+#[derive(Default)]
+struct Watcher {
+    generation: Mutex<u64>,
+    changed: Condvar,
+}
+
+impl Watcher {
+    fn start_poll_thread(&'static self) {
+        thread::spawn(move || {
+            let mut last = Self::snapshot();
+            loop {
+                thread::sleep(POLL_INTERVAL);
+                let curr = Self::snapshot();
+                if curr != last {
+                    debug!("Dev mode: change detected, reloading");
+                    last = curr;
+                    *self.generation.lock().expect("locking failed") += 1;
+                    self.changed.notify_all();
+                }
+            }
+        });
+    }
+
+    /// Latest modification time and number of watched files
+    fn snapshot() -> (Option<SystemTime>, usize) {
+        fn walk(dir: &Path, acc: &mut (Option<SystemTime>, usize)) {
+            let Ok(entries) = fs::read_dir(dir) else {
+                return;
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.is_dir() {
+                    walk(&path, acc);
+                } else {
+                    acc.0 = acc.0.max(mtime(&path));
+                    acc.1 += 1;
+                }
+            }
+        }
+
+        fn mtime(path: &Path) -> Option<SystemTime> {
+            fs::metadata(path).and_then(|m| m.modified()).ok()
+        }
+
+        let mut acc = (None, 0);
+        walk(Path::new(STATIC_DIR), &mut acc);
+        if let Some(exe) = std::env::current_exe().ok().as_deref().and_then(mtime) {
+            acc.0 = acc.0.max(Some(exe));
+        }
+        acc
+    }
+}
+
+/// Identifies this server process, so pages notice restarts
+fn instance_id() -> &'static str {
+    static INSTANCE: OnceLock<String> = OnceLock::new();
+    INSTANCE.get_or_init(|| {
+        let exe_path = std::env::current_exe().unwrap_or_else(|_| PathBuf::from("unknown"));
+        let started = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        format!("{}-{started}", exe_path.display())
+    })
+}
+
+/// Blocking SSE event stream for the `/dev/reload` response body
+pub struct ReloadEvents {
+    buf: Vec<u8>,
+    pos: usize,
+    generation: u64,
+    done: bool,
+}
+
+impl ReloadEvents {
+    pub fn new() -> Self {
+        let generation = WATCHER
+            .get()
+            .map(|w| *w.generation.lock().expect("locking failed"))
+            .unwrap_or_default();
+        Self {
+            buf: format!("event: instance\ndata: {}\n\n", instance_id()).into_bytes(),
+            pos: 0,
+            generation,
+            done: false,
+        }
+    }
+
+    /// Wait for a change (or a keepalive timeout) and queue the next event
+    fn wait_next_event(&mut self) {
+        let Some(watcher) = WATCHER.get() else {
+            self.done = true;
+            return;
+        };
+
+        let generation = watcher.generation.lock().expect("locking failed");
+        let (generation, _) = watcher
+            .changed
+            .wait_timeout_while(generation, KEEPALIVE_INTERVAL, |g| *g == self.generation)
+            .expect("locking failed");
+
+        self.buf.clear();
+        self.pos = 0;
+        if *generation != self.generation {
+            self.buf.extend_from_slice(b"event: reload\ndata:\n\n");
+            self.done = true;
+        } else {
+            // Keeps the connection alive, and lets us notice when the client is gone
+            self.buf.extend_from_slice(b": keepalive\n\n");
+        }
+    }
+}
+
+impl Read for ReloadEvents {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buf.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.wait_next_event();
+        }
+
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
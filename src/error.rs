@@ -0,0 +1,133 @@
+//! Errors returned by route handlers
+//!
+//! Each variant maps to an HTTP status and a message that is safe to show to
+//! the client. The details of [`AppError::Internal`] are only logged.
+
+use std::fmt;
+
+use hyper::StatusCode;
+
+pub const INTERNAL_ERROR_MESSAGE: &str = "Something went wrong on our side, sorry!";
+
+pub type HandlerResult = Result<astra::Response, AppError>;
+
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(String),
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    Conflict(String),
+    Unprocessable(String),
+    Internal(anyhow::Error),
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden => StatusCode::FORBIDDEN,
+            AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::Unprocessable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// What the client gets to see
+    pub fn public_message(&self) -> &str {
+        match self {
+            AppError::BadRequest(msg) | AppError::Conflict(msg) | AppError::Unprocessable(msg) => {
+                msg
+            }
+            AppError::Unauthorized => "Please log in first.",
+            AppError::Forbidden => "You are not allowed to do that.",
+            AppError::NotFound => "This page does not seem to exist, sorry!",
+            AppError::Internal(_) => INTERNAL_ERROR_MESSAGE,
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Internal(err) => write!(f, "{err:#}"),
+            _ => write!(f, "{}: {}", self.status(), self.public_message()),
+        }
+    }
+}
+
+/// Anything `?` can turn into `anyhow::Error` (redb, io, ...) is an internal
+/// error; client mistakes need to be mapped explicitly, e.g. with
+/// [`OrAppError`]
+impl<E> From<E> for AppError
+where
+    E: Into<anyhow::Error>,
+{
+    fn from(err: E) -> Self {
+        AppError::Internal(err.into())
+    }
+}
+
+/// Shorthands for mapping `Option`s and `Result`s into client errors
+pub trait OrAppError<T> {
+    fn or_not_found(self) -> Result<T, AppError>;
+    fn or_bad_request(self, msg: &str) -> Result<T, AppError>;
+}
+
+impl<T> OrAppError<T> for Option<T> {
+    fn or_not_found(self) -> Result<T, AppError> {
+        self.ok_or(AppError::NotFound)
+    }
+
+    fn or_bad_request(self, msg: &str) -> Result<T, AppError> {
+        self.ok_or_else(|| AppError::BadRequest(msg.to_owned()))
+    }
+}
+
+impl<T, E> OrAppError<T> for Result<T, E> {
+    fn or_not_found(self) -> Result<T, AppError> {
+        self.ok().or_not_found()
+    }
+
+    fn or_bad_request(self, msg: &str) -> Result<T, AppError> {
+        self.ok().or_bad_request(msg)
+    }
+}
+
+/// A route parameter; missing ones mean the route and handler disagree
+pub fn param<'p>(params: &'p matchit::Params, name: &str) -> Result<&'p str, AppError> {
+    params
+        .get(name)
+        .ok_or_else(|| AppError::Internal(anyhow::format_err!("Missing route parameter `{name}`")))
+}
+
+#[test]
+fn app_error_test() {
+    use anyhow::Context;
+
+    fn parse(s: &str) -> Result<u64, AppError> {
+        s.parse().or_bad_request("Not a number")
+    }
+    fn read() -> Result<(), AppError> {
+        std::fs::read("/nonexistent/file").context("Failed to read")?;
+        Ok(())
+    }
+
+    assert_eq!(parse("1").unwrap(), 1);
+    let err = parse("x").unwrap_err();
+    assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(err.public_message(), "Not a number");
+
+    let err = read().unwrap_err();
+    assert_eq!(err.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    // Details are logged, but never shown to clients
+    assert!(err.to_string().starts_with("Failed to read: "));
+    assert_eq!(err.public_message(), INTERNAL_ERROR_MESSAGE);
+
+    assert_eq!(
+        None::<()>.or_not_found().unwrap_err().status(),
+        StatusCode::NOT_FOUND
+    );
+}
@@ -1,16 +1,37 @@
+use std::time::SystemTime;
+
+use anyhow::bail;
 use astra::ResponseBuilder;
 use hyper::StatusCode;
-use maud::{html, Markup, DOCTYPE};
+use maud::{html, Markup, PreEscaped, Render, DOCTYPE};
+use serde::Serialize;
+
+use crate::assets::Asset;
+use crate::auth::{CurrentUser, Role, User};
+use crate::compression::{self, AcceptEncoding};
+use crate::conditional::{self, ETag};
+use crate::request_id::RequestId;
+use crate::route::Route;
+use crate::security_headers::CspNonce;
+use crate::{assets, dev, htmx};
 
-pub fn page(title: &str, content: Markup) -> Markup {
+/// A full page; `nonce` goes on every script, if there is a CSP nonce
+pub fn page(title: &str, nonce: Option<&CspNonce>, content: Markup) -> Markup {
     /// A basic header with a dynamic `page_title`.
-    pub(crate) fn head(page_title: &str) -> Markup {
+    pub(crate) fn head(page_title: &str, nonce: Option<&CspNonce>) -> Markup {
+        // Indicator styles would be inline, which the CSP doesn't allow; they
+        // are in `style.css` instead
+        let htmx_config = serde_json::json!({
+            "includeIndicatorStyles": false,
+            "inlineScriptNonce": nonce.map(CspNonce::as_str),
+        });
         html! {
             (DOCTYPE)
             html lang="en";
             head {
                 meta charset="utf-8";
-                link rel="stylesheet" type="text/css" href="/style.css";
+                meta name="htmx-config" content=(htmx_config);
+                link rel="stylesheet" type="text/css" href=(assets::url("style.css"));
                 title { "dpc - " (page_title) }
             }
         }
@@ -21,11 +42,11 @@ pub fn page(title: &str, content: Markup) -> Markup {
             header {
                 .content.split {
                     nav .column .text-column {
-                        a href="/" { "Home" }
-                        a href="/" { "Home2" }
+                        a href=(Route::home()) { "Home" }
+                        a href=(Route::home()) { "Home2" }
                     }
                     .column .img-column {
-                        img src="/favicon.ico" style="image-rendering: pixelated;" alt="dpc's avatar image";
+                        img .pixelated src=(assets::url("dpc.gif")) alt="dpc's avatar image";
                     }
                  }
             }
@@ -33,7 +54,7 @@ pub fn page(title: &str, content: Markup) -> Markup {
     }
 
     /// A static footer.
-    pub(crate) fn footer() -> Markup {
+    pub(crate) fn footer(nonce: Option<&CspNonce>) -> Markup {
         html! {
             footer {
                 .content.split {
@@ -50,43 +71,377 @@ pub fn page(title: &str, content: Markup) -> Markup {
                     }
                 }
             }
-            script src="https://unpkg.com/htmx.org@1.9.4" {};
+            script src=(htmx::url()) integrity=(htmx::integrity()) nonce=[nonce] {}
+            script src=(assets::url("htmx-errors.js")) nonce=[nonce] {}
+            @if dev::enabled() {
+                script nonce=[nonce] { (PreEscaped(dev::RELOAD_SCRIPT)) }
+            }
         }
     }
 
     html! {
-        (head(title))
+        (head(title, nonce))
         body {
             (header())
             main.content {
+                #flash {}
                 (content)
             }
-            (footer())
+            (footer(nonce))
         }
     }
 }
 
-pub(crate) fn post(id: &str, title: &str, body: &str) -> Markup {
+/// `can_edit` shows the Edit button, for users allowed to use it
+pub(crate) fn post(id: &str, title: &str, body: &str, can_edit: bool) -> Markup {
     html! {
-        article .post #id {
+        article .post #(id) {
             h2 { (title) }
 
             p {
                 (body)
             }
 
-            button hx-get={ "/post/"(id)"/edit" } hx-swap="outerHTML" hx-target={ "closest article" } { "Edit" }
+            @if can_edit {
+                button hx-get=(Route::post_edit(id)) hx-swap="outerHTML" hx-target={ "closest article" } { "Edit" }
+            }
         }
     }
 }
 
 pub(crate) fn post_edit_form(id: &str, title: &str, body: &str) -> Markup {
     html! {
-        article .post #id {
+        article .post #(id) {
             form {
                 input type="text" value=(title);
                 textarea wrap="soft" { (body) }
-                button hx-post={ "/post/"(id) } hx-swap="outerHTML" hx-target={ "closest article" } { "Submit" }
+                button hx-post=(Route::post(id)) hx-swap="outerHTML" hx-target={ "closest article" } { "Submit" }
+            }
+        }
+    }
+}
+
+/// A short notice for the `#flash` area of the page
+pub(crate) fn flash(message: &str) -> Markup {
+    html! {
+        p .flash { (message) }
+    }
+}
+
+/// Error message for htmx requests, shown in `#flash`
+pub(crate) fn error_flash(message: &str, request_id: Option<&RequestId>) -> Markup {
+    html! {
+        div .flash .error {
+            p { (message) }
+            (self::request_id(request_id))
+        }
+    }
+}
+
+/// Shown on error pages, so users can refer to the request when reporting
+/// problems
+pub(crate) fn request_id(id: Option<&RequestId>) -> Markup {
+    html! {
+        @if let Some(id) = id {
+            p .request-id { "Request id: " code { (id) } }
+        }
+    }
+}
+
+/// With `can_set_role`, a form for changing the user's role
+pub(crate) fn user_profile(id: u64, user: &User, can_set_role: bool) -> Markup {
+    html! {
+        article .user #{ "user-" (id) } {
+            h2 { (user.username) }
+            @if can_set_role {
+                form .inline method="post" action=(Route::user_role(id)) {
+                    label { "Role: "
+                        select name="role" {
+                            @for role in [Role::Viewer, Role::Editor, Role::Admin] {
+                                option value=(role) selected[role == user.role] { (role) }
+                            }
+                        }
+                    }
+                    " "
+                    button type="submit" { "Change" }
+                }
+            } @else {
+                p { "Role: " (user.role) }
+            }
+        }
+    }
+}
+
+/// Who is logged in, with links to log in or out
+pub(crate) fn account(user: Option<&CurrentUser>) -> Markup {
+    html! {
+        aside .account {
+            @if let Some(user) = user {
+                "Logged in as " strong { (user.username) } " "
+                a href=(Route::change_password()) { "Change password" }
+                form .inline method="post" action=(Route::logout()) {
+                    button type="submit" { "Log out" }
+                }
+            } @else {
+                a href=(Route::login()) { "Log in" }
+                " or "
+                a href=(Route::register()) { "register" }
+            }
+        }
+    }
+}
+
+/// An input with its label, and room for a validation error after it
+pub(crate) struct Field<'a> {
+    pub label: &'static str,
+    pub name: &'static str,
+    /// `type` of the input
+    pub kind: &'static str,
+    pub autocomplete: &'static str,
+    pub value: &'a str,
+    pub error: Option<&'a str>,
+    /// Checked by the server while it's being typed in
+    pub validate: bool,
+}
+
+impl Render for Field<'_> {
+    fn render(&self) -> Markup {
+        html! {
+            label {
+                (self.label)
+                @if self.validate {
+                    input type=(self.kind) name=(self.name) value=(self.value)
+                        autocomplete=(self.autocomplete) required
+                        hx-post=(Route::validate_field())
+                        hx-trigger="change, keyup changed delay:500ms"
+                        hx-target="next .field-error" hx-swap="innerHTML";
+                } @else {
+                    input type=(self.kind) name=(self.name) value=(self.value)
+                        autocomplete=(self.autocomplete) required;
+                }
+                span .field-error { (self.error.unwrap_or_default()) }
+            }
+        }
+    }
+}
+
+/// A form submitted by htmx, which swaps the response in its place, e.g. the
+/// form again with errors; plain form submission works too
+pub(crate) fn form(
+    id: &str,
+    action: Route,
+    fields: &[Field<'_>],
+    notice: Markup,
+    submit: &str,
+    footer: Markup,
+) -> Markup {
+    html! {
+        form .stacked #(id) method="post" action=(action) hx-post=(action)
+            hx-target="this" hx-swap="outerHTML" {
+            (notice)
+            @for field in fields {
+                (field)
+            }
+            button type="submit" { (submit) }
+            @if !footer.0.is_empty() {
+                p { (footer) }
+            }
+        }
+    }
+}
+
+/// An error about a form as a whole, rather than one of its fields
+pub(crate) fn form_error(message: &str) -> Markup {
+    html! {
+        p .form-error { (message) }
+    }
+}
+
+/// Ids of the forms, for responses to retarget to them
+pub(crate) const REGISTER_FORM: &str = "register-form";
+pub(crate) const LOGIN_FORM: &str = "login-form";
+pub(crate) const CHANGE_PASSWORD_FORM: &str = "change-password-form";
+
+/// The error for field `name` in a list of `(name, error)`s
+fn field_error<'e>(errors: &'e [(&str, String)], name: &str) -> Option<&'e str> {
+    errors
+        .iter()
+        .find(|(field, _)| *field == name)
+        .map(|(_, err)| err.as_str())
+}
+
+pub(crate) fn register_form(username: &str, errors: &[(&str, String)], notice: Markup) -> Markup {
+    form(
+        REGISTER_FORM,
+        Route::register(),
+        &[
+            Field {
+                label: "Username",
+                name: "username",
+                kind: "text",
+                autocomplete: "username",
+                value: username,
+                error: field_error(errors, "username"),
+                validate: true,
+            },
+            Field {
+                label: "Password",
+                name: "password",
+                kind: "password",
+                autocomplete: "new-password",
+                value: "",
+                error: field_error(errors, "password"),
+                validate: true,
+            },
+        ],
+        notice,
+        "Register",
+        html! { "Already registered? " a href=(Route::login()) { "Log in" } },
+    )
+}
+
+pub(crate) fn login_form(username: &str, notice: Markup) -> Markup {
+    form(
+        LOGIN_FORM,
+        Route::login(),
+        &[
+            Field {
+                label: "Username",
+                name: "username",
+                kind: "text",
+                autocomplete: "username",
+                value: username,
+                error: None,
+                validate: false,
+            },
+            Field {
+                label: "Password",
+                name: "password",
+                kind: "password",
+                autocomplete: "current-password",
+                value: "",
+                error: None,
+                validate: false,
+            },
+        ],
+        notice,
+        "Log in",
+        html! { "No account yet? " a href=(Route::register()) { "Register" } },
+    )
+}
+
+pub(crate) fn change_password_form(errors: &[(&str, String)], notice: Markup) -> Markup {
+    form(
+        CHANGE_PASSWORD_FORM,
+        Route::change_password(),
+        &[
+            Field {
+                label: "Current password",
+                name: "current_password",
+                kind: "password",
+                autocomplete: "current-password",
+                value: "",
+                error: field_error(errors, "current_password"),
+                validate: false,
+            },
+            Field {
+                label: "New password",
+                name: "password",
+                kind: "password",
+                autocomplete: "new-password",
+                value: "",
+                error: field_error(errors, "password"),
+                validate: true,
+            },
+        ],
+        notice,
+        "Change password",
+        html! {},
+    )
+}
+
+/// Sidebar showing how many times posts were saved
+pub(crate) fn saved_posts_sidebar(count: u64) -> Markup {
+    html! {
+        aside .sidebar {
+            "Saved posts: "
+            span #saved-posts-count { (count) }
+        }
+    }
+}
+
+/// How an out-of-band fragment is swapped into its target.
+///
+/// `outerHTML` is not supported, as htmx would replace the target with the
+/// wrapping element instead of the fragment itself.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[allow(dead_code)]
+pub enum OobSwap {
+    InnerHtml,
+    AfterBegin,
+    BeforeEnd,
+    BeforeBegin,
+    AfterEnd,
+}
+
+impl OobSwap {
+    fn as_str(self) -> &'static str {
+        match self {
+            OobSwap::InnerHtml => "innerHTML",
+            OobSwap::AfterBegin => "afterbegin",
+            OobSwap::BeforeEnd => "beforeend",
+            OobSwap::BeforeBegin => "beforebegin",
+            OobSwap::AfterEnd => "afterend",
+        }
+    }
+}
+
+/// A primary fragment (swapped into the `hx-target` of the request) followed
+/// by any number of `hx-swap-oob` fragments, each with its own target
+/// selector.
+pub struct OobResponse {
+    primary: Markup,
+    oob: Vec<(OobSwap, String, Markup)>,
+}
+
+impl OobResponse {
+    pub fn new(
+        primary: Markup,
+        oob: impl IntoIterator<Item = (OobSwap, &'static str, Markup)>,
+    ) -> anyhow::Result<Self> {
+        let mut validated: Vec<(OobSwap, String, Markup)> = vec![];
+
+        for (swap, target, content) in oob {
+            if target.is_empty() || target.trim() != target {
+                bail!("Invalid oob target selector: {target:?}");
+            }
+            if target.chars().any(char::is_control) {
+                bail!("Oob target selector contains control characters: {target:?}");
+            }
+            // Two `innerHTML` swaps into the same target would just overwrite each other
+            if swap == OobSwap::InnerHtml
+                && validated
+                    .iter()
+                    .any(|(s, t, _)| *s == OobSwap::InnerHtml && t == target)
+            {
+                bail!("Duplicate innerHTML oob swap into {target:?}");
+            }
+            validated.push((swap, target.to_owned(), content));
+        }
+
+        Ok(Self {
+            primary,
+            oob: validated,
+        })
+    }
+}
+
+impl Render for OobResponse {
+    fn render(&self) -> Markup {
+        html! {
+            (self.primary)
+            @for (swap, target, content) in &self.oob {
+                div hx-swap-oob={ (swap.as_str()) ":" (target) } { (content) }
             }
         }
     }
@@ -95,12 +450,22 @@ pub(crate) fn post_edit_form(id: &str, title: &str, body: &str) -> Markup {
 pub trait ResponseBuilderExt {
     type Response;
     fn cache_static(self) -> Self;
+    fn cache_immutable(self) -> Self;
     fn cache_nostore(self) -> Self;
     fn status_not_found(self) -> Self;
+    fn last_modified(self, time: SystemTime) -> Self;
+
+    /// Respond with `304 Not Modified` if the validators set so far (`ETag`,
+    /// `Last-Modified`) match the conditional headers of `req`
+    fn check_not_modified(self, req: &astra::Request) -> Result<Self, Self::Response>
+    where
+        Self: Sized;
 
-    fn body_html(self, html: maud::PreEscaped<String>) -> Self::Response;
-    fn body_static_str(self, content_type: &str, content: &'static str) -> Self::Response;
+    fn body_html(self, html: impl Render) -> Self::Response;
+    /// An embedded asset, with a strong `ETag` and `Last-Modified`
+    fn body_asset(self, req: &astra::Request, asset: &'static Asset) -> Self::Response;
     fn body_static_bytes(self, content_type: &str, content: &'static [u8]) -> Self::Response;
+    fn body_json(self, value: &impl Serialize) -> anyhow::Result<Self::Response>;
 }
 
 impl ResponseBuilderExt for ResponseBuilder {
@@ -111,6 +476,9 @@ impl ResponseBuilderExt for ResponseBuilder {
             "max-age=86400, stale-while-revalidate=86400",
         )
     }
+    fn cache_immutable(self) -> Self {
+        self.header("Cache-Control", "public, max-age=31536000, immutable")
+    }
     fn cache_nostore(self) -> Self {
         self.header("Cache-Control", "nostore")
     }
@@ -119,20 +487,97 @@ impl ResponseBuilderExt for ResponseBuilder {
         self.status(StatusCode::NOT_FOUND)
     }
 
-    fn body_html(self, html: maud::PreEscaped<String>) -> Self::Response {
+    fn last_modified(self, time: SystemTime) -> Self {
+        self.header("Last-Modified", httpdate::fmt_http_date(time))
+    }
+
+    fn check_not_modified(self, req: &astra::Request) -> Result<Self, Self::Response> {
+        let not_modified = self.headers_ref().is_some_and(|headers| {
+            conditional::is_not_modified(req.method(), req.headers(), headers)
+        });
+
+        if not_modified {
+            Err(self
+                .status(StatusCode::NOT_MODIFIED)
+                .body(astra::Body::empty())
+                .unwrap())
+        } else {
+            Ok(self)
+        }
+    }
+
+    fn body_html(self, html: impl Render) -> Self::Response {
+        let html = html.render().into_string();
         self.header("Content-Type", "text/html")
-            .body(astra::Body::new(html.into_string()))
+            .header("Content-Length", html.len())
+            .body(astra::Body::new(html))
             .unwrap()
     }
 
-    fn body_static_str(self, content_type: &str, content: &'static str) -> Self::Response {
-        self.header("Content-Type", content_type)
-            .body(astra::Body::new(content))
-            .unwrap()
+    fn body_asset(self, req: &astra::Request, asset: &'static Asset) -> Self::Response {
+        let (encoding, content) = asset.negotiate(AcceptEncoding::from_headers(req.headers()));
+
+        let mut builder = self;
+        let mut etag = ETag::strong(asset.hash).to_string();
+        if asset.has_variants() {
+            builder = builder.header("Vary", "Accept-Encoding");
+        }
+        if let Some(encoding) = encoding {
+            builder = builder.header("Content-Encoding", encoding.as_str());
+            etag = compression::with_encoding_suffix(&etag, encoding);
+        }
+
+        match builder
+            .header("ETag", etag)
+            .last_modified(asset.modified())
+            .check_not_modified(req)
+        {
+            Ok(builder) => builder.body_static_bytes(asset.content_type(), content),
+            Err(resp) => resp,
+        }
     }
+
     fn body_static_bytes(self, content_type: &str, content: &'static [u8]) -> Self::Response {
         self.header("Content-Type", content_type)
+            .header("Content-Length", content.len())
             .body(astra::Body::new(content))
             .unwrap()
     }
+
+    fn body_json(self, value: &impl Serialize) -> anyhow::Result<Self::Response> {
+        let json = serde_json::to_vec(value)?;
+        Ok(self
+            .header("Content-Type", "application/json")
+            .header("Content-Length", json.len())
+            .body(astra::Body::new(json))?)
+    }
+}
+
+#[test]
+fn oob_response_test() {
+    let resp = OobResponse::new(
+        html! { p { "primary" } },
+        [
+            (OobSwap::InnerHtml, "#flash", html! { "saved" }),
+            (OobSwap::BeforeEnd, "#log", html! { "entry" }),
+        ],
+    )
+    .unwrap();
+    assert_eq!(
+        resp.render().into_string(),
+        "<p>primary</p>\
+         <div hx-swap-oob=\"innerHTML:#flash\">saved</div>\
+         <div hx-swap-oob=\"beforeend:#log\">entry</div>"
+    );
+
+    assert!(OobResponse::new(html! {}, [(OobSwap::InnerHtml, "", html! {})]).is_err());
+    assert!(OobResponse::new(html! {}, [(OobSwap::InnerHtml, " #a", html! {})]).is_err());
+    assert!(OobResponse::new(
+        html! {},
+        [
+            (OobSwap::InnerHtml, "#a", html! {}),
+            (OobSwap::InnerHtml, "#a", html! {})
+        ]
+    )
+    .is_err());
 }
@@ -0,0 +1,128 @@
+//! Liveness, readiness and build info, for load balancers and orchestrators
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::config::SmtpConfig;
+use crate::db::Db;
+
+/// Probes come every few seconds and anyone may send them; don't sync the
+/// database or open an SMTP connection for each
+const DB_CHECK_TTL: Duration = Duration::from_secs(5);
+const SMTP_CHECK_TTL: Duration = Duration::from_secs(60);
+const SMTP_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize)]
+pub struct BuildInfo {
+    version: &'static str,
+    git_revision: &'static str,
+    build_time: &'static str,
+    features: Vec<&'static str>,
+    profile: &'static str,
+    htmx: &'static str,
+}
+
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_revision: env!("GIT_REVISION"),
+        build_time: env!("BUILD_TIME"),
+        features: env!("BUILD_FEATURES")
+            .split(',')
+            .filter(|f| !f.is_empty())
+            .collect(),
+        profile: if cfg!(debug_assertions) {
+            "debug"
+        } else {
+            "release"
+        },
+        htmx: crate::htmx::HTMX_VERSION,
+    }
+}
+
+/// The result of a check, reused for a while; failures are logged when
+/// checked, as they aren't shown to whoever probes
+struct CachedCheck {
+    name: &'static str,
+    ttl: Duration,
+    last: Mutex<Option<(Instant, bool)>>,
+}
+
+impl CachedCheck {
+    const fn new(name: &'static str, ttl: Duration) -> Self {
+        Self {
+            name,
+            ttl,
+            last: Mutex::new(None),
+        }
+    }
+
+    fn get(&self, check: impl FnOnce() -> anyhow::Result<()>) -> bool {
+        let mut last = self.last.lock().expect("locking failed");
+        if let Some((checked_at, ok)) = *last {
+            if checked_at.elapsed() < self.ttl {
+                return ok;
+            }
+        }
+
+        let ok = match check() {
+            Ok(()) => true,
+            Err(err) => {
+                warn!(
+                    check = self.name,
+                    err = format!("{err:#}"),
+                    "Readiness check failed"
+                );
+                false
+            }
+        };
+        *last = Some((Instant::now(), ok));
+        ok
+    }
+}
+
+/// Checks that the database can be read, and written to
+pub struct DbCheck(CachedCheck);
+
+impl DbCheck {
+    pub fn new() -> Self {
+        Self(CachedCheck::new("db", DB_CHECK_TTL))
+    }
+
+    pub fn check(&self, db: &Db) -> bool {
+        self.0.get(|| {
+            let db = db.get()?;
+            db.begin_read()?;
+            // An empty commit still has to write and sync the header
+            db.begin_write()?.commit()?;
+            Ok(())
+        })
+    }
+}
+
+/// Checks the SMTP server
+pub struct SmtpCheck {
+    config: SmtpConfig,
+    cached: CachedCheck,
+}
+
+impl SmtpCheck {
+    pub fn new(config: SmtpConfig) -> Self {
+        Self {
+            config,
+            cached: CachedCheck::new("smtp", SMTP_CHECK_TTL),
+        }
+    }
+
+    pub fn check(&self) -> bool {
+        self.cached.get(|| {
+            crate::smtp_transport(&self.config, Some(SMTP_CHECK_TIMEOUT))
+                .and_then(|mailer| mailer.test_connection().context("Connection test failed"))
+                .and_then(|ok| ok.then_some(()).context("Server didn't respond to NOOP"))
+        })
+    }
+}
@@ -0,0 +1,37 @@
+//! Self-hosted htmx
+//!
+//! The script is vendored in `static/vendor/htmx/` (see `just vendor-htmx`)
+//! and embedded into the binary along with other [`assets`], so the app works
+//! without access to a CDN. `build.rs` fails the build without it.
+
+use std::sync::OnceLock;
+
+use base64::Engine;
+use sha2::{Digest, Sha384};
+
+use crate::assets;
+
+pub const HTMX_VERSION: &str = "1.9.3";
+
+const PATH: &str = "vendor/htmx/htmx.min.js";
+
+pub fn asset() -> &'static assets::Asset {
+    assets::get(PATH).expect("required by build.rs")
+}
+
+/// Like any other asset's, changing with the content
+pub fn url() -> String {
+    asset().url()
+}
+
+/// Subresource Integrity value for the `integrity` attribute
+pub fn integrity() -> &'static str {
+    static INTEGRITY: OnceLock<String> = OnceLock::new();
+    INTEGRITY.get_or_init(|| {
+        let hash = Sha384::digest(asset().content);
+        format!(
+            "sha384-{}",
+            base64::engine::general_purpose::STANDARD.encode(hash)
+        )
+    })
+}
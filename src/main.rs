@@ -1,68 +1,198 @@
+mod assets;
+mod auth;
+mod backup;
+mod compression;
+mod conditional;
+mod config;
+mod data_dir;
+mod db;
+mod dev;
+mod error;
 mod fragment;
+mod health;
+mod htmx;
+mod metrics;
+mod migrations;
 mod opts;
+mod persist;
+mod query;
 mod rate_limit;
+mod request_id;
+mod route;
 mod routes;
+mod security_headers;
+mod server;
+mod shutdown;
+#[cfg(test)]
+mod test_client;
+mod tls;
 mod util;
 
+use std::backtrace::{Backtrace, BacktraceStatus};
 use std::net::{self, Ipv4Addr};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
 use std::str::FromStr;
 use std::sync::atomic::AtomicU64;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
 use clap::Parser;
-use hyper::http::HeaderValue;
-use hyper::{header, Method};
+use hyper::header;
+use hyper::http::{HeaderName, HeaderValue};
 use lettre::message::{Mailbox, MessageBuilder};
 use lettre::{Address, SmtpTransport, Transport};
 use matchit::Match;
 use rate_limit::{conventional, pre};
-use tracing::info;
-use tracing_subscriber::EnvFilter;
+use request_id::RequestId;
+use security_headers::CspNonce;
+use tracing::{error, info, info_span, Span};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+/// Probed often by infrastructure, which shouldn't get locked out
+const RATE_LIMIT_EXEMPT: &[&str] = &["/healthz", "/readyz", "/version"];
+
+/// Larger request bodies are refused
+const MAX_REQUEST_BODY: usize = 1024 * 1024;
+
+/// Pattern of the route that handled a request, in response extensions
+#[derive(Clone, Copy)]
+struct RoutePattern(&'static str);
+
+/// Address of the peer that sent a request, in request extensions
+#[derive(Clone, Copy)]
+struct PeerAddr(Option<net::SocketAddr>);
+
+/// The request body, in request extensions, as handlers only get `&Request`;
+/// read on first use, so only by handlers that parse it
+struct RequestBody {
+    body: Mutex<Option<astra::Body>>,
+    /// `None` if it couldn't be read or was larger than [`MAX_REQUEST_BODY`]
+    content: OnceLock<Option<Vec<u8>>>,
+}
 
-type Router = matchit::Router<
-    &'static [(
-        Method,
-        for<'a> fn(&Service, &'a astra::Request, &'a matchit::Params) -> astra::Response,
-    )],
->;
+impl RequestBody {
+    fn new(body: astra::Body) -> Self {
+        Self {
+            body: Mutex::new(Some(body)),
+            content: OnceLock::new(),
+        }
+    }
+
+    fn content(&self) -> Option<&[u8]> {
+        self.content
+            .get_or_init(|| read_body(self.body.lock().expect("locking failed").take()?))
+            .as_deref()
+    }
+}
+
+/// Who the request is logged in as, in request extensions; looked up on first
+/// use, see [`Service::current_user`]
+#[derive(Default)]
+struct SessionUser(OnceLock<Option<auth::CurrentUser>>);
 
 #[derive(Default)]
 struct State {
     count: AtomicU64,
+    saved_posts: AtomicU64,
 }
 
 #[derive(Clone)]
 pub struct Service {
     state: Arc<State>,
-    db: Arc<redb::Database>,
-    router: Router,
+    // Holds the data directory lock for as long as the service is alive
+    _data_dir: Arc<data_dir::DataDir>,
+    db: Arc<db::Db>,
+    shutdown: Arc<shutdown::Shutdown>,
+    router: route::Router,
+    metrics_config: Arc<config::MetricsConfig>,
+    db_check: Arc<health::DbCheck>,
+    smtp_check: Option<Arc<health::SmtpCheck>>,
+    /// `Strict-Transport-Security`, if serving over TLS
+    hsts: Option<HeaderValue>,
+    /// Unless disabled
+    security_headers: Option<Arc<config::SecurityHeadersConfig>>,
+    /// Session cookies are only sent over HTTPS, if it's served
+    secure_cookies: bool,
+    /// Where to find the client's IP without a peer address
+    client_ip_header: Option<HeaderName>,
     pre_rate_limiter: pre::FastPreRateLimiter,
     rate_limiter: conventional::RateLimiter,
+    /// Keyed by username
+    login_rate_limiter: conventional::RateLimiter<String>,
 }
 
 impl Service {
-    fn new() -> anyhow::Result<Self> {
-        let router = {
-            let mut router = Router::new();
-            router.insert("/", &[(Method::GET, Self::home)])?;
-            router.insert("/favicon.ico", &[(Method::GET, Self::favicon_ico)])?;
-            router.insert("/style.css", &[(Method::GET, Self::style_css)])?;
-            router.insert("/count", &[(Method::POST, Self::count)])?;
-            router.insert("/user/:id", &[(Method::GET, Self::get_user)])?;
-            router.insert("/post/:id", &[(Method::POST, Self::save_post)])?;
-            router.insert("/post/:id/edit", &[(Method::GET, Self::edit_post)])?;
-            router
-        };
-
-        let db = redb::Database::create("./target/db.redb")?;
+    fn new(config: &config::Config) -> anyhow::Result<Self> {
+        let router = route::router()?;
+
+        let data_dir = data_dir::DataDir::open(&config.data_dir)?;
+        let db = data_dir.open_db()?;
+        info!(path = %data_dir.path().display(), "Opened data directory");
+        migrations::run(&db, false)?;
+
+        let limits = &config.rate_limit;
+        let state = State::default();
+        let pre_rate_limiter =
+            pre::FastPreRateLimiter::new(limits.pre.threshold, limits.pre.window_secs);
+        let rate_limiter = conventional::RateLimiter::new(
+            limits.conventional.threshold,
+            limits.conventional.window_secs,
+        );
+        persist::load(&db, &state, &pre_rate_limiter, &rate_limiter, limits)?;
+        let login_rate_limiter =
+            conventional::RateLimiter::new(limits.login.threshold, limits.login.window_secs);
+
+        let db = Arc::new(db::Db::new(db));
+
+        let backup_dir = config.backup.as_ref().map(|backup| backup.dir.as_path());
+        backup::remove_stale_files(&data_dir, backup_dir);
+        backup::start_socket_thread(&db, &data_dir)?;
+
+        if let Some(backup) = &config.backup {
+            backup::start_periodic_thread(
+                &db,
+                backup.dir.clone(),
+                Duration::from_secs(backup.interval_secs),
+                backup.retention,
+            );
+        }
 
         Ok(Self {
-            state: Default::default(),
+            state: state.into(),
             router,
-            db: db.into(),
-            pre_rate_limiter: pre::FastPreRateLimiter::new(20, 60),
-            rate_limiter: conventional::RateLimiter::new(10, 60),
+            metrics_config: Arc::new(config.metrics.clone()),
+            db_check: Arc::new(health::DbCheck::new()),
+            smtp_check: config
+                .smtp
+                .clone()
+                .map(|smtp| Arc::new(health::SmtpCheck::new(smtp))),
+            hsts: config
+                .tls
+                .as_ref()
+                .filter(|tls| 0 < tls.hsts_max_age_secs)
+                .map(|tls| {
+                    HeaderValue::from_str(&format!("max-age={}", tls.hsts_max_age_secs))
+                        .expect("can't fail")
+                }),
+            security_headers: config
+                .security_headers
+                .enabled
+                .then(|| Arc::new(config.security_headers.clone())),
+            secure_cookies: config.tls.is_some(),
+            client_ip_header: config.client_ip_header(),
+            _data_dir: data_dir.into(),
+            db,
+            shutdown: Default::default(),
+            pre_rate_limiter,
+            rate_limiter,
+            login_rate_limiter,
         })
     }
 
@@ -72,13 +202,21 @@ impl Service {
             // If a handler is found, insert the route parameters into the request
             // extensions, and call it
             Ok(Match { value, params }) => {
-                if let Some((_method, f)) = value.iter().find(|(method, _)| req.method() == method)
+                let mut resp = if let Some((_method, guard, f)) = value
+                    .handlers
+                    .iter()
+                    .find(|(method, _, _)| req.method() == method)
                 {
                     let params = params.clone();
-                    (f)(self, req, &params)
+                    self.authorize(req, guard, &params)
+                        .and_then(|()| (f)(self, req, &params))
+                        .unwrap_or_else(|err| self.app_error(req, err))
                 } else {
                     self.not_found_404(req)
-                }
+                };
+                resp.extensions_mut().insert(RoutePattern(value.pattern));
+                Span::current().record("route", value.pattern);
+                resp
             }
             // Otherwise return a 404
             Err(_) => self.not_found_404(req),
@@ -90,50 +228,193 @@ impl Service {
         req: &astra::Request,
         f: impl FnOnce(&astra::Request) -> astra::Response,
     ) -> astra::Response {
-        let mut session = None;
-        for (k, v) in RequestExt(req).iter_cookies() {
-            if k == "session" {
-                session = Some(v.to_owned());
-            }
+        if let Some(session) = RequestExt(req).session_id() {
+            metrics::get().observe_session(session);
         }
-        let mut resp = f(req);
+        f(req)
+    }
 
-        if session.is_none() {
-            resp.headers_mut().insert(
-                header::SET_COOKIE,
-                HeaderValue::from_str("session=booo").expect("can't fail"),
-            );
+    /// Whether the request may be handled by a handler behind `guard`
+    fn authorize(
+        &self,
+        req: &astra::Request,
+        guard: &route::Guard,
+        params: &matchit::Params,
+    ) -> Result<(), error::AppError> {
+        if let route::Guard::Public = guard {
+            return Ok(());
+        }
+        let user = self
+            .current_user(req)?
+            .ok_or(error::AppError::Unauthorized)?;
+        if !guard.allows(user, params) {
+            return Err(error::AppError::Forbidden);
+        }
+        Ok(())
+    }
+
+    /// The user the request is logged in as, if any
+    fn current_user<'r>(
+        &self,
+        req: &'r astra::Request,
+    ) -> anyhow::Result<Option<&'r auth::CurrentUser>> {
+        let Some(cached) = req.extensions().get::<SessionUser>() else {
+            return Ok(None);
+        };
+        if let Some(user) = cached.0.get() {
+            return Ok(user.as_ref());
         }
+        let user = match RequestExt(req).session_id() {
+            Some(token) => auth::session_user(&*self.db.get()?, token)?,
+            None => None,
+        };
+        Ok(cached.0.get_or_init(|| user).as_ref())
+    }
+
+    /// Turn a panic in `f` into a 500 response, instead of a dropped connection
+    fn handle_panics(
+        &self,
+        req: &astra::Request,
+        f: impl FnOnce(&astra::Request) -> astra::Response,
+    ) -> astra::Response {
+        // The panic itself is logged by the hook set in `init_panic_hook`
+        panic::catch_unwind(AssertUnwindSafe(|| f(req))).unwrap_or_else(|_| {
+            metrics::get().inc_panics();
+            self.internal_server_error_500(req)
+        })
+    }
 
+    fn handle_compression(
+        &self,
+        req: &astra::Request,
+        f: impl FnOnce(&astra::Request) -> astra::Response,
+    ) -> astra::Response {
+        let accept = compression::AcceptEncoding::from_headers(req.headers());
+        compression::compress_html_response(f(req), accept)
+    }
+
+    fn handle_security_headers(
+        &self,
+        req: &astra::Request,
+        f: impl FnOnce(&astra::Request) -> astra::Response,
+    ) -> astra::Response {
+        let mut resp = f(req);
+        if let Some(config) = &self.security_headers {
+            security_headers::apply(config, RequestExt(req).csp_nonce(), resp.headers_mut());
+        }
         resp
     }
 
+    /// Once shutdown started, turn new requests away, so the ones in flight can
+    /// finish
+    fn handle_shutdown(
+        &self,
+        req: &astra::Request,
+        f: impl FnOnce(&astra::Request) -> astra::Response,
+    ) -> astra::Response {
+        match self.shutdown.track() {
+            Some(in_flight) => {
+                let mut resp = f(req);
+                resp.extensions_mut().insert(in_flight);
+                resp
+            }
+            None => self.service_unavailable_503(req),
+        }
+    }
+
     fn handle_rate_limiting(
         &self,
         req: &astra::Request,
-        info: &astra::ConnectionInfo,
         f: impl FnOnce(&astra::Request) -> astra::Response,
-    ) -> (astra::Response, Option<net::SocketAddr>) {
-        let peer_addr = info.peer_addr();
-        let peer_ip = peer_addr
-            .map(|s| s.ip())
+    ) -> astra::Response {
+        if RATE_LIMIT_EXEMPT.contains(&req.uri().path()) {
+            return f(req);
+        }
+
+        let peer_ip = RequestExt(req)
+            .client_ip(self.client_ip_header.as_ref())
             .unwrap_or(net::IpAddr::V4(Ipv4Addr::UNSPECIFIED));
 
-        (
-            if self.pre_rate_limiter.rate_limit(peer_ip) && self.rate_limiter.rate_limit(peer_ip) {
-                self.too_many_requests_429(req)
-            } else {
-                f(req)
-            },
-            peer_addr,
-        )
+        if self.pre_rate_limiter.rate_limit(peer_ip) {
+            metrics::get().inc_pre_rate_limit_trips();
+            if self.rate_limiter.rate_limit(&peer_ip) {
+                metrics::get().inc_rate_limited();
+                return self.too_many_requests_429(req);
+            }
+        }
+        f(req)
     }
 }
 
 pub struct RequestExt<'a>(&'a hyper::Request<astra::Body>);
 
 impl<'a> RequestExt<'a> {
-    fn iter_cookies(&self) -> impl Iterator<Item = (&str, &str)> {
+    fn peer_addr(&self) -> Option<net::SocketAddr> {
+        self.0.extensions().get::<PeerAddr>().and_then(|p| p.0)
+    }
+
+    /// The peer's IP or, with no peer address (a unix socket, which only a
+    /// local proxy should reach), the client's IP as reported by the proxy in
+    /// `header`
+    fn client_ip(&self, header: Option<&HeaderName>) -> Option<net::IpAddr> {
+        if let Some(addr) = self.peer_addr() {
+            return Some(addr.ip());
+        }
+        self.0
+            .headers()
+            .get(header?)?
+            .to_str()
+            .ok()?
+            // Of a list, the last entry is the one added by the proxy itself
+            .rsplit(',')
+            .next()?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    fn request_id(&self) -> Option<&'a RequestId> {
+        self.0.extensions().get::<RequestId>()
+    }
+
+    fn csp_nonce(&self) -> Option<&'a CspNonce> {
+        self.0.extensions().get::<CspNonce>()
+    }
+
+    /// Whether the request was made by htmx, rather than a full page load
+    fn is_htmx(&self) -> bool {
+        self.0
+            .headers()
+            .get("HX-Request")
+            .is_some_and(|v| v.as_bytes() == b"true")
+    }
+
+    /// The query string, parsed into `T`; see [`query`]
+    fn query<T: serde::de::DeserializeOwned>(&self) -> Result<T, error::AppError> {
+        query::parse(self.0.uri().query())
+    }
+
+    /// The `application/x-www-form-urlencoded` body, parsed into `T`
+    fn form<T: serde::de::DeserializeOwned>(&self) -> Result<T, error::AppError> {
+        use error::OrAppError;
+
+        let body = self
+            .0
+            .extensions()
+            .get::<RequestBody>()
+            .and_then(RequestBody::content)
+            .or_bad_request("The request body is too large")?;
+        query::parse_form(body)
+    }
+
+    fn session_id(&self) -> Option<&'a str> {
+        self.iter_cookies()
+            .filter(|(k, _)| *k == auth::SESSION_COOKIE)
+            .map(|(_, v)| v)
+            .last()
+    }
+
+    fn iter_cookies(&self) -> impl Iterator<Item = (&'a str, &'a str)> {
         self.0
             .headers()
             .get_all(header::COOKIE)
@@ -145,22 +426,95 @@ impl<'a> RequestExt<'a> {
     }
 }
 
-impl astra::Service for Service {
-    fn call(
+impl Service {
+    /// Handle a request from [`server`], or the test client
+    fn handle(
         &self,
-        req: hyper::Request<astra::Body>,
-        info: astra::ConnectionInfo,
+        mut req: hyper::Request<astra::Body>,
+        peer_addr: Option<net::SocketAddr>,
     ) -> astra::Response {
-        let (resp, peer_addr) = self.handle_rate_limiting(&req, &info, |req| {
-            self.handle_session(req, |req| self.route(req))
+        use crate::util::DisplayOption;
+
+        let start = Instant::now();
+        let request_id = RequestId::from_headers_or_generate(req.headers());
+
+        // `route` gets recorded once the request is routed
+        let span = info_span!(
+            "request",
+            method = %req.method(),
+            route = tracing::field::Empty,
+            peer = %DisplayOption(peer_addr),
+            request_id = %request_id,
+        );
+        let _guard = span.enter();
+
+        req.extensions_mut().insert(PeerAddr(peer_addr));
+        req.extensions_mut().insert(request_id.clone());
+        let body = std::mem::replace(req.body_mut(), astra::Body::empty());
+        req.extensions_mut().insert(RequestBody::new(body));
+        req.extensions_mut().insert(SessionUser::default());
+        if self.security_headers.is_some() {
+            match CspNonce::generate() {
+                Ok(nonce) => {
+                    req.extensions_mut().insert(nonce);
+                }
+                // Scripts get blocked, but the page is still usable
+                Err(err) => error!(err = format!("{err:#}"), "Failed to generate CSP nonce"),
+            }
+        }
+
+        let mut resp = self.handle_security_headers(&req, |req| {
+            self.handle_shutdown(req, |req| {
+                self.handle_rate_limiting(req, |req| {
+                    self.handle_panics(req, |req| {
+                        self.handle_compression(req, |req| {
+                            self.handle_session(req, |req| self.route(req))
+                        })
+                    })
+                })
+            })
         });
 
-        use crate::util::DisplayOption;
+        resp.headers_mut().insert(
+            request_id::HEADER,
+            HeaderValue::from_str(request_id.as_str()).expect("validated"),
+        );
+        if let Some(hsts) = &self.hsts {
+            resp.headers_mut()
+                .insert(header::STRICT_TRANSPORT_SECURITY, hsts.clone());
+        }
+
+        let route = resp
+            .extensions()
+            .get::<RoutePattern>()
+            .map_or(metrics::UNMATCHED_ROUTE, |r| r.0);
+        metrics::get().observe_request(route, resp.status(), start.elapsed());
+
+        let req_header = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+        let size = resp
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok());
+        // Not the id itself, which would let anyone reading the logs take over
+        // the session
+        let session = RequestExt(&req)
+            .session_id()
+            .map(|id| conditional::short_hash(id.as_bytes()));
+        // Only if a handler looked the user up already
+        let user_id = req
+            .extensions()
+            .get::<SessionUser>()
+            .and_then(|user| user.0.get()?.as_ref())
+            .map(|user| user.id);
         info!(
-            status = %resp.status(),
-            method = %req.method(),
+            status = resp.status().as_u16(),
             path = %req.uri(),
-            peer = %DisplayOption(peer_addr),
+            duration_ms = start.elapsed().as_secs_f64() * 1000.0,
+            size = %DisplayOption(size),
+            user_agent = %DisplayOption(req_header(header::USER_AGENT)),
+            referer = %DisplayOption(req_header(header::REFERER)),
+            session = %DisplayOption(session),
+            user_id = %DisplayOption(user_id),
             "request"
         );
         resp
@@ -168,63 +522,205 @@ impl astra::Service for Service {
 }
 
 fn main() -> anyhow::Result<()> {
-    init_logging()?;
+    // Before parsing options, which can come from the environment
+    let env_file = dotenv::dotenv();
+
+    let opts = opts::Opts::parse();
+    let config = config::Config::load(opts.config.as_deref(), &opts.overrides)?;
 
-    if let Ok(path) = dotenv::dotenv() {
+    let _log_guard = init_logging(&config.log)?;
+    init_panic_hook();
+    if let Ok(path) = env_file {
         info!(path = %path.display(), "Loaded env file");
     }
 
-    let args = opts::Opts::parse();
+    match opts.command {
+        Some(opts::Command::Config {
+            command: opts::ConfigCommand::Print,
+        }) => {
+            print!("{}", config.to_toml_redacted()?);
+            return Ok(());
+        }
+        Some(opts::Command::Backup { path }) => {
+            match data_dir::DataDir::open(&config.data_dir) {
+                Ok(data_dir) => backup::snapshot(&data_dir.open_db()?, &path)?,
+                // Most likely in use by a running server, which can take it instead
+                Err(err) => backup::request_snapshot(&config.data_dir, &path)
+                    .map_err(|request_err| err.context(format!("{request_err:#}")))?,
+            }
+            println!("Backup written to {}", path.display());
+            return Ok(());
+        }
+        Some(opts::Command::Restore { path }) => {
+            let data_dir = data_dir::DataDir::open(&config.data_dir)?;
+            backup::restore(&data_dir, &path)?;
+            println!("Restored from {}", path.display());
+            return Ok(());
+        }
+        Some(opts::Command::SetRole { username, role }) => {
+            let data_dir = data_dir::DataDir::open(&config.data_dir)
+                .context("While the server is running, admins can change roles on user profiles")?;
+            let db = data_dir.open_db()?;
+            migrations::run(&db, false)?;
+            let username = auth::normalize_username(&username);
+            auth::set_role(&db, &username, role)?;
+            println!("{username} is now {role}");
+            return Ok(());
+        }
+        Some(opts::Command::Migrate { dry_run }) => {
+            let data_dir = data_dir::DataDir::open(&config.data_dir)?;
+            let db = data_dir.open_db()?;
+            let applied = migrations::run(&db, dry_run)?;
+            if applied.is_empty() {
+                println!(
+                    "Schema is up to date (version {})",
+                    migrations::latest_version()
+                );
+            }
+            for name in applied {
+                println!(
+                    "{} {name}",
+                    if dry_run { "Would apply:" } else { "Applied:" }
+                );
+            }
+            return Ok(());
+        }
+        None => {}
+    }
 
-    // send_email()?;
+    if config.dev {
+        dev::enable();
+    }
 
-    let service = Service::new()?;
+    // send_email(config.smtp.as_ref().context("SMTP not configured")?)?;
 
-    let server = astra::Server::bind(args.listen);
+    let service = Service::new(&config)?;
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout_secs);
 
-    info!("Listening on {}", server.local_addr()?);
-    server
-        .serve(service)
-        .context("Failed to start http server")?;
+    let listen = config.listen_addr();
+    let listener = server::Listener::open(&listen, config.socket_mode())?;
+    if matches!(listener, server::Listener::Unix(_)) && config.client_ip_header.is_none() {
+        bail!(
+            "Listening on a unix socket takes `client_ip_header`, so clients can be told apart for rate limiting"
+        );
+    }
+    let acceptor = match &config.tls {
+        Some(tls) => {
+            let resolver = Arc::new(tls::CertResolver::load(&tls.cert, &tls.key)?);
+            resolver.reload_on_sighup()?;
+
+            if let Some(redirect_listen) = &tls.redirect_listen {
+                let server = astra::Server::bind(redirect_listen);
+                info!("Redirecting to HTTPS from {}", server.local_addr()?);
+                let redirect = tls::Redirect {
+                    // Behind a unix socket, HTTPS is on the default port of a proxy
+                    https_port: listener.local_port().unwrap_or(443),
+                };
+                std::thread::spawn(move || {
+                    if let Err(err) = server.serve(redirect) {
+                        error!(%err, "HTTPS redirect server failed");
+                    }
+                });
+            }
+            Some(tls::acceptor(resolver))
+        }
+        None => None,
+    };
+
+    info!(tls = acceptor.is_some(), "Listening on {listener}");
+    shutdown::serve_until_signal(
+        {
+            let service = service.clone();
+            move || server::serve(listener, acceptor, service)
+        },
+        service,
+        shutdown_timeout,
+    )
+}
 
-    Ok(())
+/// `None` if the body is larger than [`MAX_REQUEST_BODY`], or reading it failed
+fn read_body(body: astra::Body) -> Option<Vec<u8>> {
+    let mut content = vec![];
+    for chunk in body {
+        let chunk = chunk.ok()?;
+        if MAX_REQUEST_BODY < content.len() + chunk.len() {
+            return None;
+        }
+        content.extend_from_slice(&chunk);
+    }
+    Some(content)
 }
 
-fn init_logging() -> anyhow::Result<()> {
-    let subscriber = tracing_subscriber::fmt()
-        .with_writer(std::io::stderr) // Print to stderr
-        .with_env_filter(
-            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
-        )
-        .finish();
+/// Log panics like everything else, so they end up in the same place, with
+/// the request id of the request being handled
+fn init_panic_hook() {
+    panic::set_hook(Box::new(|info| {
+        let backtrace = Backtrace::capture();
+        if backtrace.status() == BacktraceStatus::Captured {
+            error!(panic = %info, %backtrace, "Panic");
+        } else {
+            error!(panic = %info, "Panic");
+        }
+    }));
+}
 
-    tracing::subscriber::set_global_default(subscriber)
+/// The returned guard flushes the log file when dropped
+fn init_logging(config: &config::LogConfig) -> anyhow::Result<Option<WorkerGuard>> {
+    let (writer, guard) = match &config.file {
+        Some(path) => {
+            let rotation = match config.rotation {
+                config::LogRotation::Hourly => Rotation::HOURLY,
+                config::LogRotation::Daily => Rotation::DAILY,
+                config::LogRotation::Never => Rotation::NEVER,
+            };
+            let mut builder = RollingFileAppender::builder().rotation(rotation);
+            if let Some(name) = path.file_name() {
+                builder = builder.filename_prefix(name.to_string_lossy());
+            }
+            if let Some(max_files) = config.max_files {
+                builder = builder.max_log_files(max_files);
+            }
+            let dir = path.parent().unwrap_or(Path::new("."));
+            let appender = builder
+                .build(dir)
+                .with_context(|| format!("Failed to open log file {}", path.display()))?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            (BoxMakeWriter::new(writer), Some(guard))
+        }
+        None => (BoxMakeWriter::new(std::io::stderr), None), // Print to stderr
+    };
+
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(config.file.is_none());
+    let layer = match config.format {
+        config::LogFormat::Full => layer.boxed(),
+        config::LogFormat::Compact => layer.compact().boxed(),
+        config::LogFormat::Pretty => layer.pretty().boxed(),
+        config::LogFormat::Json => layer
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    };
+
+    tracing_subscriber::registry()
+        .with(layer)
+        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .try_init()
         .context("Failed to set tracing subscriber")?;
 
-    Ok(())
+    Ok(guard)
 }
 
-fn send_email() -> anyhow::Result<()> {
-    let smtp_hostname = std::env::var("SMTP_HOSTNAME")?;
-    let smtp_port = std::env::var("SMTP_PORT")?;
-    let smtp_username = std::env::var("SMTP_USER")?;
-    let smtp_password = std::env::var("SMTP_PASSWORD")?;
-    let smtp_to = std::env::var("SMTP_TO")?;
-    let smtp_from = std::env::var("SMTP_FROM")?;
-
+fn send_email(smtp: &config::SmtpConfig) -> anyhow::Result<()> {
     let email = MessageBuilder::new()
-        .to(Mailbox::new(None, Address::from_str(&smtp_to)?))
-        .from(Mailbox::new(None, Address::from_str(&smtp_from)?))
+        .to(Mailbox::new(None, Address::from_str(&smtp.to)?))
+        .from(Mailbox::new(None, Address::from_str(&smtp.from)?))
         .subject("Test Email")
         .body("Hello from Rust!".to_owned())?;
 
-    let mailer = SmtpTransport::relay(&smtp_hostname)?
-        .port(FromStr::from_str(&smtp_port).context("Failed to parse port number")?)
-        .credentials(lettre::transport::smtp::authentication::Credentials::new(
-            smtp_username,
-            smtp_password,
-        ))
-        .build();
+    let mailer = smtp_transport(smtp, None)?;
 
     mailer
         .test_connection()
@@ -234,3 +730,19 @@ fn send_email() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+fn smtp_transport(
+    smtp: &config::SmtpConfig,
+    timeout: Option<Duration>,
+) -> anyhow::Result<SmtpTransport> {
+    let mut builder = SmtpTransport::relay(&smtp.hostname)?
+        .port(smtp.port)
+        .credentials(lettre::transport::smtp::authentication::Credentials::new(
+            smtp.user.clone(),
+            smtp.password.expose().to_owned(),
+        ));
+    if timeout.is_some() {
+        builder = builder.timeout(timeout);
+    }
+    Ok(builder.build())
+}
@@ -0,0 +1,296 @@
+//! Prometheus metrics, served at `/metrics` in the text exposition format
+//!
+//! Everything is recorded into a single global registry, so the backup thread
+//! and migrations can report database timings without a `Service` at hand.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use hyper::{header, HeaderMap, StatusCode};
+
+use crate::config::MetricsConfig;
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0,
+];
+
+/// A session counts as active if seen within this long
+const SESSION_ACTIVE_WINDOW: Duration = Duration::from_secs(15 * 60);
+/// Bounds the memory used for session tracking
+const MAX_TRACKED_SESSIONS: usize = 100_000;
+
+/// Route label for requests that didn't match any route
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+pub fn get() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::default)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TxKind {
+    Read,
+    Write,
+}
+
+impl TxKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            TxKind::Read => "read",
+            TxKind::Write => "write",
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    /// By route pattern and status code
+    requests: Mutex<BTreeMap<(&'static str, u16), Histogram>>,
+    pre_rate_limit_trips: AtomicU64,
+    rate_limited: AtomicU64,
+    panics: AtomicU64,
+    /// Last time each session (by hash of its id) was seen
+    sessions: Mutex<HashMap<u64, Instant>>,
+    /// By transaction kind and what it was for
+    db_transactions: Mutex<BTreeMap<(TxKind, &'static str), Histogram>>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Cumulative, like the `le` buckets they are rendered as
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bucket, le) in self.buckets.iter_mut().zip(BUCKETS) {
+            if secs <= le {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        for (bucket, le) in self.buckets.iter().zip(BUCKETS) {
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{le}\"}} {bucket}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{{{labels}}} {}", self.count);
+    }
+}
+
+impl Metrics {
+    pub fn observe_request(&self, route: &'static str, status: StatusCode, duration: Duration) {
+        self.requests
+            .lock()
+            .expect("locking failed")
+            .entry((route, status.as_u16()))
+            .or_default()
+            .observe(duration);
+    }
+
+    pub fn inc_pre_rate_limit_trips(&self) {
+        self.pre_rate_limit_trips.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_panics(&self) {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn observe_session(&self, id: &str) {
+        let mut hasher = DefaultHasher::new();
+        id.hash(&mut hasher);
+        let now = Instant::now();
+
+        let mut sessions = self.sessions.lock().expect("locking failed");
+        if MAX_TRACKED_SESSIONS <= sessions.len() {
+            sessions.retain(|_, seen| now.duration_since(*seen) < SESSION_ACTIVE_WINDOW);
+        }
+        if sessions.len() < MAX_TRACKED_SESSIONS {
+            sessions.insert(hasher.finish(), now);
+        }
+    }
+
+    pub fn observe_db_transaction(&self, kind: TxKind, op: &'static str, duration: Duration) {
+        self.db_transactions
+            .lock()
+            .expect("locking failed")
+            .entry((kind, op))
+            .or_default()
+            .observe(duration);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        {
+            let requests = self.requests.lock().expect("locking failed");
+            out.push_str("# HELP http_requests_total HTTP requests, by route pattern and status\n");
+            out.push_str("# TYPE http_requests_total counter\n");
+            for ((route, status), histogram) in requests.iter() {
+                let _ = writeln!(
+                    out,
+                    "http_requests_total{{route=\"{route}\",status=\"{status}\"}} {}",
+                    histogram.count
+                );
+            }
+            out.push_str(
+                "# HELP http_request_duration_seconds Time until the response headers were ready\n",
+            );
+            out.push_str("# TYPE http_request_duration_seconds histogram\n");
+            for ((route, status), histogram) in requests.iter() {
+                histogram.render(
+                    &mut out,
+                    "http_request_duration_seconds",
+                    &format!("route=\"{route}\",status=\"{status}\""),
+                );
+            }
+        }
+
+        out.push_str(
+            "# HELP rate_limit_pre_trips_total Requests over the `pre` limit, passed on to the conventional limiter to decide\n",
+        );
+        out.push_str("# TYPE rate_limit_pre_trips_total counter\n");
+        let _ = writeln!(
+            out,
+            "rate_limit_pre_trips_total {}",
+            self.pre_rate_limit_trips.load(Ordering::Relaxed)
+        );
+
+        out.push_str("# HELP rate_limit_rejections_total Requests rejected with a 429\n");
+        out.push_str("# TYPE rate_limit_rejections_total counter\n");
+        let _ = writeln!(
+            out,
+            "rate_limit_rejections_total {}",
+            self.rate_limited.load(Ordering::Relaxed)
+        );
+
+        out.push_str(
+            "# HELP http_handler_panics_total Requests answered with a 500 after a panic\n",
+        );
+        out.push_str("# TYPE http_handler_panics_total counter\n");
+        let _ = writeln!(
+            out,
+            "http_handler_panics_total {}",
+            self.panics.load(Ordering::Relaxed)
+        );
+
+        let active_sessions = {
+            let now = Instant::now();
+            let mut sessions = self.sessions.lock().expect("locking failed");
+            sessions.retain(|_, seen| now.duration_since(*seen) < SESSION_ACTIVE_WINDOW);
+            sessions.len()
+        };
+        out.push_str("# HELP active_sessions Sessions seen within the last 15 minutes\n");
+        out.push_str("# TYPE active_sessions gauge\n");
+        let _ = writeln!(out, "active_sessions {active_sessions}");
+
+        out.push_str(
+            "# HELP db_transaction_duration_seconds redb transactions, from start to commit\n",
+        );
+        out.push_str("# TYPE db_transaction_duration_seconds histogram\n");
+        for ((kind, op), histogram) in self.db_transactions.lock().expect("locking failed").iter() {
+            histogram.render(
+                &mut out,
+                "db_transaction_duration_seconds",
+                &format!("kind=\"{}\",op=\"{op}\"", kind.as_str()),
+            );
+        }
+
+        out
+    }
+}
+
+/// Whether a `/metrics` request is allowed, either by peer IP or by
+/// `Authorization: Bearer <token>`
+pub fn access_allowed(
+    config: &MetricsConfig,
+    peer_ip: Option<IpAddr>,
+    headers: &HeaderMap,
+) -> bool {
+    if peer_ip.is_some_and(|ip| config.allowed_ips.contains(&ip)) {
+        return true;
+    }
+
+    let Some(token) = &config.token else {
+        return false;
+    };
+    headers
+        .get_all(header::AUTHORIZATION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .filter_map(|v| v.strip_prefix("Bearer "))
+        .any(|provided| constant_time_eq(provided.trim().as_bytes(), token.expose().as_bytes()))
+}
+
+/// Doesn't leak how much of the token matched through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[test]
+fn metrics_test() {
+    use hyper::http::HeaderValue;
+
+    let metrics = Metrics::default();
+    metrics.observe_request("/", StatusCode::OK, Duration::from_millis(2));
+    metrics.observe_request("/", StatusCode::OK, Duration::from_secs(10));
+    metrics.inc_pre_rate_limit_trips();
+    metrics.inc_rate_limited();
+    metrics.observe_session("a");
+    metrics.observe_session("a");
+    metrics.observe_session("b");
+
+    let out = metrics.render();
+    assert!(out.contains("http_requests_total{route=\"/\",status=\"200\"} 2\n"));
+    assert!(out.contains(
+        "http_request_duration_seconds_bucket{route=\"/\",status=\"200\",le=\"0.0025\"} 1\n"
+    ));
+    assert!(out.contains(
+        "http_request_duration_seconds_bucket{route=\"/\",status=\"200\",le=\"+Inf\"} 2\n"
+    ));
+    assert!(out.contains("rate_limit_pre_trips_total 1\n"));
+    assert!(out.contains("rate_limit_rejections_total 1\n"));
+    assert!(out.contains("active_sessions 2\n"));
+
+    let config: MetricsConfig = toml::from_str("token = \"s3cret\"").unwrap();
+    let mut headers = HeaderMap::new();
+    assert!(access_allowed(
+        &config,
+        Some("127.0.0.1".parse().unwrap()),
+        &headers
+    ));
+    assert!(!access_allowed(
+        &config,
+        Some("10.0.0.1".parse().unwrap()),
+        &headers
+    ));
+    headers.insert(
+        header::AUTHORIZATION,
+        HeaderValue::from_static("Bearer s3cret"),
+    );
+    assert!(access_allowed(&config, None, &headers));
+    headers.insert(
+        header::AUTHORIZATION,
+        HeaderValue::from_static("Bearer s3cre"),
+    );
+    assert!(!access_allowed(&config, None, &headers));
+}
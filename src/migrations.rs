@@ -0,0 +1,138 @@
+//! Database schema versioning
+//!
+//! The schema version is the number of [`MIGRATIONS`] applied so far, kept in
+//! the `metadata` table. Each migration runs in its own write transaction,
+//! together with the version bump, so it's either fully applied or not at
+//! all.
+
+use std::time::Instant;
+
+use anyhow::{bail, Context};
+use redb::{ReadableTable, TableDefinition, TableError, WriteTransaction};
+use tracing::info;
+
+use crate::metrics::{self, TxKind};
+use crate::{auth, persist};
+
+pub const METADATA_TABLE: TableDefinition<&str, u64> = TableDefinition::new("metadata");
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+type Migration = fn(&WriteTransaction) -> anyhow::Result<()>;
+
+/// All migrations, in order. Only ever append to this list.
+const MIGRATIONS: &[(&str, Migration)] = &[
+    ("initial schema", |tx| {
+        tx.open_table(METADATA_TABLE)?;
+        Ok(())
+    }),
+    ("counters and rate limit snapshots", |tx| {
+        tx.open_table(persist::COUNTERS_TABLE)?;
+        tx.open_table(persist::RATE_LIMIT_SNAPSHOT_TABLE)?;
+        Ok(())
+    }),
+    ("users and sessions", |tx| {
+        tx.open_table(auth::USERS_TABLE)?;
+        tx.open_table(auth::USERNAMES_TABLE)?;
+        tx.open_table(auth::SESSIONS_TABLE)?;
+        Ok(())
+    }),
+    ("session indexes", auth::index_sessions),
+];
+
+pub fn latest_version() -> u64 {
+    MIGRATIONS.len() as u64
+}
+
+/// Schema version of a database, without changing anything
+pub fn schema_version(db: &redb::Database) -> anyhow::Result<u64> {
+    let tx = db.begin_read()?;
+    let table = match tx.open_table(METADATA_TABLE) {
+        Ok(table) => table,
+        Err(TableError::TableDoesNotExist(_)) => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+    let version = table.get(SCHEMA_VERSION_KEY)?.map(|v| v.value());
+    Ok(version.unwrap_or(0))
+}
+
+fn read_version(tx: &WriteTransaction) -> anyhow::Result<u64> {
+    let table = tx.open_table(METADATA_TABLE)?;
+    let version = table.get(SCHEMA_VERSION_KEY)?.map(|v| v.value());
+    Ok(version.unwrap_or(0))
+}
+
+fn write_version(tx: &WriteTransaction, version: u64) -> anyhow::Result<()> {
+    tx.open_table(METADATA_TABLE)?
+        .insert(SCHEMA_VERSION_KEY, version)?;
+    Ok(())
+}
+
+/// Bring the database schema up to date
+///
+/// With `dry_run` all pending migrations are executed in a single transaction
+/// that is then aborted, so nothing is changed. Returns the names of the
+/// migrations that were (or would be) applied.
+pub fn run(db: &redb::Database, dry_run: bool) -> anyhow::Result<Vec<&'static str>> {
+    let mut applied = vec![];
+
+    let tx = db.begin_write()?;
+    let mut version = read_version(&tx)?;
+    let mut tx = Some(tx);
+
+    if latest_version() < version {
+        bail!(
+            "Database schema version {version} is newer than the latest one known to this build ({}); refusing to start. Upgrade the application, or restore a backup made with this version",
+            latest_version()
+        );
+    }
+
+    for (i, (name, migration)) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let start = Instant::now();
+        let curr_tx = match tx.take() {
+            Some(tx) => tx,
+            None => db.begin_write()?,
+        };
+
+        migration(&curr_tx).with_context(|| format!("Migration {} ({name}) failed", i + 1))?;
+        version = i as u64 + 1;
+        write_version(&curr_tx, version)?;
+
+        if dry_run {
+            // keep going in the same transaction, so later migrations see the
+            // effects of earlier ones
+            tx = Some(curr_tx);
+            info!(version, name, "Migration would be applied");
+        } else {
+            curr_tx.commit()?;
+            metrics::get().observe_db_transaction(TxKind::Write, "migration", start.elapsed());
+            info!(version, name, "Applied migration");
+        }
+        applied.push(*name);
+    }
+
+    if let Some(tx) = tx {
+        tx.abort()?;
+    }
+
+    Ok(applied)
+}
+
+#[test]
+fn migrations_test() {
+    let path =
+        std::env::temp_dir().join(format!("htmx-demo-migrations-test-{}", std::process::id()));
+    let db = redb::Database::create(&path).unwrap();
+
+    assert_eq!(run(&db, true).unwrap().len(), MIGRATIONS.len());
+    // dry run didn't change anything
+    assert_eq!(run(&db, false).unwrap().len(), MIGRATIONS.len());
+    assert!(run(&db, false).unwrap().is_empty());
+
+    let tx = db.begin_write().unwrap();
+    write_version(&tx, latest_version() + 1).unwrap();
+    tx.commit().unwrap();
+    assert!(run(&db, false).is_err());
+
+    drop(db);
+    std::fs::remove_file(&path).unwrap();
+}
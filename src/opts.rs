@@ -1,8 +1,169 @@
-use clap::Parser;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::auth;
+use crate::config::{LogFormat, LogRotation};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Opts {
-    #[arg(long, short, default_value = "localhost:3000")]
-    pub listen: String,
+    /// Path to a TOML config file
+    #[arg(long, env = "HTMX_DEMO_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub overrides: ConfigOverrides,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Settings overriding the ones from the config file
+#[derive(Args)]
+pub struct ConfigOverrides {
+    /// `host:port`, `unix:<path>`, or `systemd` for socket activation
+    #[arg(long, short, env = "HTMX_DEMO_LISTEN")]
+    pub listen: Option<String>,
+
+    /// Permissions of a `unix:` socket, in octal (e.g. `660`)
+    #[arg(long, env = "HTMX_DEMO_SOCKET_MODE")]
+    pub socket_mode: Option<String>,
+
+    /// Header the proxy in front of a unix socket puts the client's IP in
+    #[arg(long, env = "HTMX_DEMO_CLIENT_IP_HEADER")]
+    pub client_ip_header: Option<String>,
+
+    /// PEM certificate chain; enables HTTPS on `listen`
+    #[arg(long, env = "HTMX_DEMO_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for `tls_cert`
+    #[arg(long, env = "HTMX_DEMO_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Plain HTTP listener redirecting to HTTPS
+    #[arg(long, env = "HTMX_DEMO_TLS_REDIRECT_LISTEN")]
+    pub tls_redirect_listen: Option<String>,
+
+    /// `max-age` of the `Strict-Transport-Security` header; 0 disables it
+    #[arg(long, env = "HTMX_DEMO_HSTS_MAX_AGE_SECS")]
+    pub hsts_max_age_secs: Option<u64>,
+
+    /// Serve static files from disk and live-reload pages on changes
+    #[arg(long)]
+    pub dev: bool,
+
+    #[arg(long, env = "HTMX_DEMO_LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    /// Log into this file instead of stderr
+    #[arg(long, env = "HTMX_DEMO_LOG_FILE")]
+    pub log_file: Option<PathBuf>,
+
+    #[arg(long, env = "HTMX_DEMO_LOG_ROTATION")]
+    pub log_rotation: Option<LogRotation>,
+
+    /// Number of rotated log files to keep
+    #[arg(long, env = "HTMX_DEMO_LOG_MAX_FILES")]
+    pub log_max_files: Option<usize>,
+
+    /// Directory for the database and other persistent files
+    #[arg(long, env = "HTMX_DEMO_DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+
+    /// Seconds to wait for in-flight requests on SIGTERM/SIGINT
+    #[arg(long, env = "HTMX_DEMO_SHUTDOWN_TIMEOUT_SECS")]
+    pub shutdown_timeout_secs: Option<u64>,
+
+    #[arg(long, env = "HTMX_DEMO_PRE_RATE_LIMIT_THRESHOLD")]
+    pub pre_rate_limit_threshold: Option<usize>,
+
+    #[arg(long, env = "HTMX_DEMO_PRE_RATE_LIMIT_WINDOW_SECS")]
+    pub pre_rate_limit_window_secs: Option<u64>,
+
+    #[arg(long, env = "HTMX_DEMO_RATE_LIMIT_THRESHOLD")]
+    pub rate_limit_threshold: Option<usize>,
+
+    #[arg(long, env = "HTMX_DEMO_RATE_LIMIT_WINDOW_SECS")]
+    pub rate_limit_window_secs: Option<u64>,
+
+    #[arg(long, env = "SMTP_HOSTNAME")]
+    pub smtp_hostname: Option<String>,
+
+    #[arg(long, env = "SMTP_PORT")]
+    pub smtp_port: Option<u16>,
+
+    #[arg(long, env = "SMTP_USER")]
+    pub smtp_user: Option<String>,
+
+    #[arg(long, env = "SMTP_PASSWORD", hide_env_values = true)]
+    pub smtp_password: Option<String>,
+
+    #[arg(long, env = "SMTP_FROM")]
+    pub smtp_from: Option<String>,
+
+    #[arg(long, env = "SMTP_TO")]
+    pub smtp_to: Option<String>,
+
+    /// Directory for periodic online backups
+    #[arg(long, env = "HTMX_DEMO_BACKUP_DIR")]
+    pub backup_dir: Option<PathBuf>,
+
+    #[arg(long, env = "HTMX_DEMO_BACKUP_INTERVAL_SECS")]
+    pub backup_interval_secs: Option<u64>,
+
+    /// Number of most recent periodic backups to keep
+    #[arg(long, env = "HTMX_DEMO_BACKUP_RETENTION")]
+    pub backup_retention: Option<usize>,
+
+    /// Peer IPs allowed to scrape `/metrics` without a token (comma separated)
+    #[arg(long, env = "HTMX_DEMO_METRICS_ALLOWED_IPS", value_delimiter = ',')]
+    pub metrics_allowed_ips: Option<Vec<IpAddr>>,
+
+    /// Bearer token for `/metrics`
+    #[arg(long, env = "HTMX_DEMO_METRICS_TOKEN", hide_env_values = true)]
+    pub metrics_token: Option<String>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Write a consistent snapshot of the database to `path`
+    ///
+    /// If a server is running in the data directory, it takes the snapshot
+    /// instead. It can also make periodic ones (`--backup-dir`).
+    Backup { path: PathBuf },
+    /// Replace the database with a backup from `path`, after validating it
+    ///
+    /// The server must not be running. The previous database is kept in the
+    /// data directory.
+    Restore { path: PathBuf },
+    /// Apply pending database migrations and exit
+    Migrate {
+        /// Only show what would be applied, without changing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Change what a user may do; new users are viewers
+    ///
+    /// Needs exclusive access to the data directory, so it's meant for making
+    /// the first admin; while the server runs, admins can change roles on
+    /// user profiles.
+    SetRole {
+        username: String,
+        #[arg(value_enum)]
+        role: auth::Role,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Print the effective configuration, with secrets redacted
+    Print,
 }
@@ -0,0 +1,154 @@
+//! In-memory state kept across restarts
+//!
+//! Saved on shutdown and loaded on startup: the counters, and snapshots of
+//! the rate limiters, so that restarting doesn't reset everyone's limits.
+
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+use redb::{ReadableTable, TableDefinition};
+
+use crate::config::RateLimitConfig;
+use crate::metrics::{self, TxKind};
+use crate::rate_limit::{conventional, pre};
+use crate::util::unix_now;
+use crate::State;
+
+pub const COUNTERS_TABLE: TableDefinition<&str, u64> = TableDefinition::new("counters");
+pub const RATE_LIMIT_SNAPSHOT_TABLE: TableDefinition<&str, &[u8]> =
+    TableDefinition::new("rate_limit_snapshot");
+
+const COUNT_KEY: &str = "count";
+const SAVED_POSTS_KEY: &str = "saved_posts";
+
+/// Unix time (`u64`, little endian) the snapshots were taken at
+const TAKEN_AT_KEY: &str = "taken_at";
+const PRE_KEY: &str = "pre";
+/// `<ip> <count>` lines
+const CONVENTIONAL_KEY: &str = "conventional";
+
+pub fn load(
+    db: &redb::Database,
+    state: &State,
+    pre: &pre::FastPreRateLimiter,
+    conventional: &conventional::RateLimiter,
+    limits: &RateLimitConfig,
+) -> anyhow::Result<()> {
+    let tx = db.begin_read()?;
+
+    let counters = tx.open_table(COUNTERS_TABLE)?;
+    for (key, counter) in [
+        (COUNT_KEY, &state.count),
+        (SAVED_POSTS_KEY, &state.saved_posts),
+    ] {
+        if let Some(value) = counters.get(key)? {
+            counter.store(value.value(), Ordering::Relaxed);
+        }
+    }
+
+    let snapshots = tx.open_table(RATE_LIMIT_SNAPSHOT_TABLE)?;
+    let Some(taken_at) = snapshots
+        .get(TAKEN_AT_KEY)?
+        .and_then(|v| v.value().try_into().ok())
+        .map(u64::from_le_bytes)
+    else {
+        return Ok(());
+    };
+    // Anything older than a window would have been forgotten anyway
+    let age = unix_now().saturating_sub(taken_at);
+    if age < limits.pre.window_secs {
+        if let Some(snapshot) = snapshots.get(PRE_KEY)? {
+            pre.restore(snapshot.value());
+        }
+    }
+    if age < limits.conventional.window_secs {
+        if let Some(snapshot) = snapshots.get(CONVENTIONAL_KEY)? {
+            conventional.restore(decode_counts(snapshot.value()));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn save(
+    db: &redb::Database,
+    state: &State,
+    pre: &pre::FastPreRateLimiter,
+    conventional: &conventional::RateLimiter,
+) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let tx = db.begin_write()?;
+    {
+        let mut counters = tx.open_table(COUNTERS_TABLE)?;
+        counters.insert(COUNT_KEY, state.count.load(Ordering::Relaxed))?;
+        counters.insert(SAVED_POSTS_KEY, state.saved_posts.load(Ordering::Relaxed))?;
+
+        let mut snapshots = tx.open_table(RATE_LIMIT_SNAPSHOT_TABLE)?;
+        snapshots.insert(TAKEN_AT_KEY, unix_now().to_le_bytes().as_slice())?;
+        snapshots.insert(PRE_KEY, pre.snapshot().as_slice())?;
+        snapshots.insert(
+            CONVENTIONAL_KEY,
+            encode_counts(&conventional.snapshot()).as_slice(),
+        )?;
+    }
+    tx.commit()?;
+    metrics::get().observe_db_transaction(TxKind::Write, "persist", start.elapsed());
+    Ok(())
+}
+
+fn encode_counts(counts: &[(IpAddr, u16)]) -> Vec<u8> {
+    counts
+        .iter()
+        .map(|(ip, count)| format!("{ip} {count}\n"))
+        .collect::<String>()
+        .into_bytes()
+}
+
+/// Malformed lines are skipped; a lost count only makes limits more lenient
+fn decode_counts(bytes: &[u8]) -> Vec<(IpAddr, u16)> {
+    String::from_utf8_lossy(bytes)
+        .lines()
+        .filter_map(|line| {
+            let (ip, count) = line.split_once(' ')?;
+            Some((ip.parse().ok()?, count.parse().ok()?))
+        })
+        .collect()
+}
+
+#[test]
+fn persist_test() {
+    let path = std::env::temp_dir().join(format!("htmx-demo-persist-test-{}", std::process::id()));
+    let db = redb::Database::create(&path).unwrap();
+    crate::migrations::run(&db, false).unwrap();
+
+    let limits = RateLimitConfig::default();
+    let new_limiters = || {
+        (
+            pre::FastPreRateLimiter::new(limits.pre.threshold, limits.pre.window_secs),
+            conventional::RateLimiter::new(
+                limits.conventional.threshold,
+                limits.conventional.window_secs,
+            ),
+        )
+    };
+    let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+    let state = State::default();
+    state.count.store(7, Ordering::Relaxed);
+    let (pre, conventional) = new_limiters();
+    for _ in 0..limits.conventional.threshold {
+        assert!(!conventional.rate_limit(&ip));
+    }
+    assert!(conventional.rate_limit(&ip));
+    save(&db, &state, &pre, &conventional).unwrap();
+
+    let state = State::default();
+    let (pre, conventional) = new_limiters();
+    load(&db, &state, &pre, &conventional, &limits).unwrap();
+    assert_eq!(state.count.load(Ordering::Relaxed), 7);
+    assert!(conventional.rate_limit(&ip));
+
+    drop(db);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,68 @@
+//! Query strings and form bodies, parsed into typed structs
+//!
+//! Fields missing from the query take their `#[serde(default)]`, and keys
+//! repeated in it (`?tag=a&tag=b`) can be collected into a `Vec`.
+
+use serde::de::DeserializeOwned;
+
+use crate::error::AppError;
+
+pub fn parse<T: DeserializeOwned>(query: Option<&str>) -> Result<T, AppError> {
+    serde_html_form::from_str(query.unwrap_or_default())
+        .map_err(|err| AppError::BadRequest(format!("Invalid query string: {err}")))
+}
+
+/// An `application/x-www-form-urlencoded` body, which is encoded just like a
+/// query string
+pub fn parse_form<T: DeserializeOwned>(body: &[u8]) -> Result<T, AppError> {
+    serde_html_form::from_bytes(body)
+        .map_err(|err| AppError::BadRequest(format!("Invalid form data: {err}")))
+}
+
+#[test]
+fn query_parse_test() {
+    use hyper::StatusCode;
+    use serde::Deserialize;
+
+    #[derive(Deserialize, PartialEq, Debug)]
+    #[serde(rename_all = "lowercase")]
+    enum Sort {
+        New,
+        Top,
+    }
+
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct Query {
+        #[serde(default = "first_page")]
+        page: u32,
+        sort: Option<Sort>,
+        #[serde(default)]
+        tag: Vec<String>,
+    }
+
+    fn first_page() -> u32 {
+        1
+    }
+
+    assert_eq!(
+        parse::<Query>(None).unwrap(),
+        Query {
+            page: 1,
+            sort: None,
+            tag: vec![],
+        }
+    );
+    assert_eq!(
+        parse::<Query>(Some("page=2&sort=new&tag=a&tag=b%20c")).unwrap(),
+        Query {
+            page: 2,
+            sort: Some(Sort::New),
+            tag: vec!["a".into(), "b c".into()],
+        }
+    );
+
+    for query in ["page=x", "sort=old", "page=-1"] {
+        let err = parse::<Query>(Some(query)).unwrap_err();
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST, "{query}");
+    }
+}
@@ -1,16 +1,17 @@
 use std::collections::HashMap;
+use std::hash::Hash;
 use std::net::IpAddr;
 use std::sync::atomic::{AtomicU16, Ordering};
 use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
-struct RateLimiterInner {
+struct RateLimiterInner<K> {
     threshold: usize,
-    buckets: [HashMap<IpAddr, AtomicU16>; 2],
+    buckets: [HashMap<K, AtomicU16>; 2],
     curr_bucket: u8,
 }
 
-impl RateLimiterInner {
+impl<K> RateLimiterInner<K> {
     pub(crate) fn new(threshold: usize) -> Self {
         Self {
             threshold,
@@ -26,12 +27,24 @@ impl RateLimiterInner {
     }
 }
 
-#[derive(Clone)]
-pub struct RateLimiter {
-    inner: Arc<RwLock<RateLimiterInner>>,
+/// Counts requests per key: peer IPs by default, but e.g. account names work
+/// just as well
+pub struct RateLimiter<K = IpAddr> {
+    inner: Arc<RwLock<RateLimiterInner<K>>>,
 }
 
-impl RateLimiter {
+impl<K> Clone for RateLimiter<K> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<K> RateLimiter<K>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+{
     pub fn new(threshold: usize, window_secs: u64) -> Self {
         let s = Self {
             inner: Arc::new(RwLock::new(RateLimiterInner::new(threshold))),
@@ -42,14 +55,18 @@ impl RateLimiter {
         s
     }
 
-    pub fn rate_limit(&self, peer_ip: std::net::IpAddr) -> bool {
+    pub fn rate_limit<Q>(&self, key: &Q) -> bool
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
         loop {
             let read = self.inner.read().expect("locking failed");
 
-            if let Some(entry) = read.buckets[read.curr_bucket as usize].get(&peer_ip) {
+            if let Some(entry) = read.buckets[read.curr_bucket as usize].get(key) {
                 let curr = entry.load(Ordering::Relaxed) as usize;
                 let prev = read.buckets[(read.curr_bucket as usize + 1) % 2]
-                    .get(&peer_ip)
+                    .get(key)
                     .map(|entry| entry.load(Ordering::Relaxed))
                     .unwrap_or(0) as usize;
 
@@ -67,13 +84,40 @@ impl RateLimiter {
             let mut write = self.inner.write().expect("locking failed");
             let curr_bucket = write.curr_bucket;
             write.buckets[curr_bucket as usize]
-                .entry(peer_ip)
+                .entry(key.to_owned())
                 .or_default();
         }
     }
 }
 
-impl RateLimiter {
+impl<K> RateLimiter<K>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+{
+    /// Requests counted for each key within the current window
+    pub fn snapshot(&self) -> Vec<(K, u16)> {
+        let read = self.inner.read().expect("locking failed");
+        let mut counts = HashMap::<K, u16>::new();
+        for bucket in &read.buckets {
+            for (key, count) in bucket {
+                let entry = counts.entry(key.clone()).or_default();
+                *entry = entry.saturating_add(count.load(Ordering::Relaxed));
+            }
+        }
+        counts.into_iter().collect()
+    }
+
+    /// Count requests from a [`Self::snapshot`] as if they happened just now
+    pub fn restore(&self, counts: impl IntoIterator<Item = (K, u16)>) {
+        let mut write = self.inner.write().expect("locking failed");
+        let curr_bucket = write.curr_bucket as usize;
+        for (key, count) in counts {
+            let entry = write.buckets[curr_bucket].entry(key).or_default();
+            let entry = entry.get_mut();
+            *entry = entry.saturating_add(count);
+        }
+    }
+
     pub fn start_timer_thread(&self, window_secs: u64) {
         let s = Arc::downgrade(&self.inner);
         let tick = (window_secs / 2) + 1;
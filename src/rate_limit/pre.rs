@@ -74,6 +74,10 @@ pub struct FastPreRateLimiter {
 }
 
 impl FastPreRateLimiter {
+    /// Bucket counters are `u8`, so they must not overflow even when a single
+    /// bucket takes all the hits
+    pub const MAX_THRESHOLD: usize = u8::MAX as usize - FastPreRateLimiterInner::BUCKET_NUM;
+
     pub fn new(threshold: usize, window_secs: u64) -> Self {
         let s = Self {
             inner: Arc::new(FastPreRateLimiterInner::new(threshold)),
@@ -87,6 +91,28 @@ impl FastPreRateLimiter {
     pub fn rate_limit(&self, peer_ip: IpAddr) -> bool {
         self.inner.rate_limit(peer_ip)
     }
+
+    /// Raw bucket counters
+    pub fn snapshot(&self) -> Vec<u8> {
+        self.inner
+            .buckets
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Add counters from a [`Self::snapshot`]; ones of a different size
+    /// (from a build with a different bucket layout) are ignored
+    pub fn restore(&self, snapshot: &[u8]) {
+        if snapshot.len() != self.inner.buckets.len() {
+            return;
+        }
+        for (bucket, count) in self.inner.buckets.iter().zip(snapshot) {
+            let _ = bucket.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |curr| {
+                Some(curr.saturating_add(*count))
+            });
+        }
+    }
 }
 
 impl FastPreRateLimiter {
@@ -0,0 +1,89 @@
+//! Request ids, for correlating logs of a single request (and with whatever
+//! proxy sits in front of us)
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+use hyper::HeaderMap;
+
+pub const HEADER: &str = "x-request-id";
+
+/// Longer incoming ids are replaced with generated ones
+const MAX_LEN: usize = 128;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RequestId(String);
+
+impl RequestId {
+    /// Use the id sent by the client or proxy, if it looks sane, otherwise
+    /// generate a new one
+    pub fn from_headers_or_generate(headers: &HeaderMap) -> Self {
+        headers
+            .get(HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|id| is_valid(id))
+            .map(|id| Self(id.to_owned()))
+            .unwrap_or_else(Self::generate)
+    }
+
+    /// `<random per-process prefix>-<counter>`: unique, cheap, and sorts in
+    /// order of arrival within a process
+    fn generate() -> Self {
+        static PREFIX: OnceLock<String> = OnceLock::new();
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let prefix = PREFIX.get_or_init(|| {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u32(std::process::id());
+            format!("{:08x}", hasher.finish() as u32)
+        });
+        Self(format!(
+            "{prefix}-{}",
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Safe to log and echo back in a header
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+#[test]
+fn request_id_test() {
+    use hyper::http::HeaderValue;
+
+    let mut headers = HeaderMap::new();
+    let generated = RequestId::from_headers_or_generate(&headers);
+    assert!(is_valid(generated.as_str()));
+    assert_ne!(generated, RequestId::from_headers_or_generate(&headers));
+
+    headers.insert(HEADER, HeaderValue::from_static("abc-123"));
+    assert_eq!(
+        RequestId::from_headers_or_generate(&headers).as_str(),
+        "abc-123"
+    );
+
+    headers.insert(HEADER, HeaderValue::from_static("<script>"));
+    assert_ne!(
+        RequestId::from_headers_or_generate(&headers).as_str(),
+        "<script>"
+    );
+}
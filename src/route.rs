@@ -0,0 +1,287 @@
+//! The route table: URL patterns, their typed parameters and handlers
+//!
+//! Each route gets a params struct (in [`params`]) parsed from the matched
+//! path before its handlers are called, and a constructor on [`Route`] for
+//! building URLs to it, e.g. `Route::post_edit(id).to_string()`. Handlers
+//! can be guarded (`GET(guard) Service::handler`), so only some users get to
+//! call them; without a guard, anyone can.
+
+use std::fmt;
+
+use hyper::Method;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+
+use crate::auth::{CurrentUser, Role};
+use crate::error::{self, HandlerResult, OrAppError};
+use crate::Service;
+
+/// A handler, with its route parameters not parsed yet
+pub type Handler =
+    for<'a> fn(&Service, &'a astra::Request, &'a matchit::Params) -> error::HandlerResult;
+
+#[derive(Clone, Copy)]
+pub struct Endpoint {
+    pub pattern: &'static str,
+    pub handlers: &'static [(Method, Guard, Handler)],
+}
+
+/// Who may call a handler; checked before parsing route parameters
+#[derive(Clone, Copy)]
+pub enum Guard {
+    Public,
+    /// Logged in users with at least this role
+    Role(Role),
+    /// Logged in users owning what the route is about, as told by the
+    /// function, or with at least the role
+    OwnerOr(Role, fn(&CurrentUser, &matchit::Params) -> bool),
+}
+
+impl Guard {
+    pub fn allows(&self, user: &CurrentUser, params: &matchit::Params) -> bool {
+        match self {
+            Guard::Public => true,
+            Guard::Role(role) => user.has_role(*role),
+            Guard::OwnerOr(role, owns) => user.has_role(*role) || owns(user, params),
+        }
+    }
+}
+
+pub type Router = matchit::Router<Endpoint>;
+
+/// Route parameters that can be parsed from a matched path
+pub trait FromParams: Sized {
+    fn from_params(params: &matchit::Params) -> Result<Self, error::AppError>;
+}
+
+macro_rules! guard {
+    () => {
+        Guard::Public
+    };
+    ($guard:expr) => {
+        $guard
+    };
+}
+
+macro_rules! routes {
+    ($(
+        $(#[$meta:meta])*
+        $name:ident, $Variant:ident: $pattern:literal { $($field:ident: $ty:ty),* }
+            => [$($method:ident $(($guard:expr))? $handler:path),+ $(,)?],
+    )*) => {
+        pub mod params {
+            $(
+                #[derive(Clone, PartialEq, Eq, Debug)]
+                pub struct $Variant {
+                    $(pub $field: $ty,)*
+                }
+            )*
+        }
+
+        /// A URL of one of the routes; `Display` renders it
+        // Not every route is linked to from within the app
+        #[allow(dead_code)]
+        #[derive(Clone, PartialEq, Eq, Debug)]
+        pub enum Route {
+            $($Variant(params::$Variant),)*
+        }
+
+        #[allow(dead_code)]
+        impl Route {
+            $(
+                $(#[$meta])*
+                pub fn $name($($field: impl Into<$ty>),*) -> Self {
+                    Route::$Variant(params::$Variant { $($field: $field.into()),* })
+                }
+            )*
+        }
+
+        impl fmt::Display for Route {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match self {
+                    $(Route::$Variant(params) => params.fmt(f),)*
+                }
+            }
+        }
+
+        $(
+            impl FromParams for params::$Variant {
+                #[allow(unused_variables)]
+                fn from_params(params: &matchit::Params) -> Result<Self, error::AppError> {
+                    Ok(Self {
+                        $($field: parse_param(params, stringify!($field))?,)*
+                    })
+                }
+            }
+
+            impl fmt::Display for params::$Variant {
+                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                    write_path(f, $pattern, &[$((stringify!($field), &self.$field),)*])
+                }
+            }
+        )*
+
+        pub fn router() -> anyhow::Result<Router> {
+            let mut router = Router::new();
+            $({
+                static HANDLERS: &[(Method, Guard, Handler)] = &[$((Method::$method, guard!($($guard)?), {
+                    fn handler(
+                        service: &Service,
+                        req: &astra::Request,
+                        params: &matchit::Params,
+                    ) -> HandlerResult {
+                        $handler(service, req, FromParams::from_params(params)?)
+                    }
+                    handler
+                }),)+];
+                router.insert($pattern, Endpoint { pattern: $pattern, handlers: HANDLERS })?;
+            })*
+            Ok(router)
+        }
+    };
+}
+
+routes! {
+    home, Home: "/" {} => [GET Service::home],
+    favicon_ico, FaviconIco: "/favicon.ico" {} => [GET Service::favicon_ico],
+    static_asset, StaticAsset: "/static/:hash/*path" { hash: String, path: String }
+        => [GET Service::static_asset],
+    dev_reload, DevReload: "/dev/reload" {} => [GET Service::dev_reload],
+    metrics, Metrics: "/metrics" {} => [GET Service::metrics],
+    healthz, Healthz: "/healthz" {} => [GET Service::healthz],
+    readyz, Readyz: "/readyz" {} => [GET Service::readyz],
+    version, Version: "/version" {} => [GET Service::version],
+    count, Count: "/count" {} => [POST Service::count],
+    user, User: "/user/:id" { id: u64 }
+        => [GET(Guard::OwnerOr(Role::Admin, is_user)) Service::get_user],
+    user_role, UserRole: "/user/:id/role" { id: u64 } => [POST(ADMINS) Service::set_user_role],
+    post, Post: "/post/:id" { id: String } => [POST(EDITORS) Service::save_post],
+    post_edit, PostEdit: "/post/:id/edit" { id: String } => [GET(EDITORS) Service::edit_post],
+    register, Register: "/register" {} => [GET Service::register_page, POST Service::register],
+    login, Login: "/login" {} => [GET Service::login_page, POST Service::login],
+    logout, Logout: "/logout" {} => [POST Service::logout],
+    change_password, ChangePassword: "/account/password" {} => [
+        GET(LOGGED_IN) Service::change_password_page,
+        POST(LOGGED_IN) Service::change_password,
+    ],
+    validate_field, ValidateField: "/account/validate" {} => [POST Service::validate_field],
+}
+
+const LOGGED_IN: Guard = Guard::Role(Role::Viewer);
+const EDITORS: Guard = Guard::Role(Role::Editor);
+const ADMINS: Guard = Guard::Role(Role::Admin);
+
+/// The `:id` of the route is the user's own
+fn is_user(user: &CurrentUser, params: &matchit::Params) -> bool {
+    params.get("id").and_then(|id| id.parse().ok()) == Some(user.id)
+}
+
+/// What to escape in a path segment, besides non-ASCII
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// A route parameter; ones that don't parse are the client's fault
+fn parse_param<T: std::str::FromStr>(
+    params: &matchit::Params,
+    name: &str,
+) -> Result<T, error::AppError> {
+    let message = format!("Invalid `{name}` in the URL");
+    percent_decode_str(error::param(params, name)?)
+        .decode_utf8()
+        .or_bad_request(&message)?
+        .parse()
+        .or_bad_request(&message)
+}
+
+/// Fill the `:name` and `*name` parts of a pattern in, percent-encoded
+///
+/// A `*name` value may span segments, so its `/`s are kept.
+fn write_path(
+    f: &mut fmt::Formatter<'_>,
+    pattern: &str,
+    values: &[(&str, &dyn fmt::Display)],
+) -> fmt::Result {
+    let mut rest = pattern;
+    while let Some(start) = rest.find([':', '*']) {
+        f.write_str(&rest[..start])?;
+        let end = rest[start..]
+            .find('/')
+            .map_or(rest.len(), |end| start + end);
+        let name = &rest[start + 1..end];
+        let (_, value) = values
+            .iter()
+            .find(|(n, _)| *n == name)
+            .expect("every parameter has a field");
+        let value = value.to_string();
+        if rest[start..].starts_with('*') {
+            for (i, segment) in value.split('/').enumerate() {
+                if 0 < i {
+                    f.write_str("/")?;
+                }
+                write!(f, "{}", utf8_percent_encode(segment, SEGMENT))?;
+            }
+        } else {
+            write!(f, "{}", utf8_percent_encode(&value, SEGMENT))?;
+        }
+        rest = &rest[end..];
+    }
+    f.write_str(rest)
+}
+
+#[test]
+fn route_test() {
+    assert_eq!(Route::home().to_string(), "/");
+    assert_eq!(Route::post_edit("a-b").to_string(), "/post/a-b/edit");
+    assert_eq!(
+        Route::static_asset("abc", "img/x.gif").to_string(),
+        "/static/abc/img/x.gif"
+    );
+    assert_eq!(
+        Route::post_edit("a/b c?ä").to_string(),
+        "/post/a%2Fb%20c%3F%C3%A4/edit"
+    );
+
+    let router = router().unwrap();
+    for (route, pattern) in [
+        (Route::user(42u64), "/user/:id"),
+        (
+            Route::static_asset("abc", "vendor/htmx/htmx.min.js"),
+            "/static/:hash/*path",
+        ),
+    ] {
+        assert_eq!(
+            router.at(&route.to_string()).unwrap().value.pattern,
+            pattern
+        );
+    }
+
+    let matched = router.at("/user/42").unwrap();
+    assert_eq!(
+        params::User::from_params(&matched.params).unwrap(),
+        params::User { id: 42 }
+    );
+    let path = Route::post("a/b c%").to_string();
+    let matched = router.at(&path).unwrap();
+    assert_eq!(
+        params::Post::from_params(&matched.params).unwrap(),
+        params::Post {
+            id: "a/b c%".to_owned()
+        }
+    );
+    let matched = router.at("/user/foo").unwrap();
+    assert_eq!(
+        params::User::from_params(&matched.params)
+            .unwrap_err()
+            .status(),
+        hyper::StatusCode::BAD_REQUEST
+    );
+}
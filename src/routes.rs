@@ -1,14 +1,58 @@
 use std::sync::atomic::Ordering;
 
+use anyhow::Context;
 use astra::{Body, Request, Response, ResponseBuilder};
-use hyper::{Method, StatusCode};
-use maud::html;
+use hyper::http::HeaderValue;
+use hyper::{header, Method, StatusCode};
+use maud::{html, Markup};
+use serde::Deserialize;
+use tracing::{debug, error};
 
-use crate::fragment::{self, ResponseBuilderExt};
-use crate::Service;
+use crate::auth::Role;
+use crate::error::{self, AppError, HandlerResult, OrAppError};
+use crate::fragment::{self, OobResponse, OobSwap, ResponseBuilderExt};
+use crate::route::{params, Route};
+use crate::{assets, auth, dev, health, metrics, RequestExt, Service};
+
+const USERNAME_TAKEN: &str = "This username is taken";
+
+#[derive(Deserialize)]
+struct Credentials {
+    #[serde(default)]
+    username: String,
+    #[serde(default)]
+    password: String,
+}
+
+/// Only the given dependencies, e.g. `/readyz?check=db`, or all of them
+#[derive(Deserialize)]
+struct ReadyzQuery {
+    #[serde(default)]
+    check: Vec<Dependency>,
+}
+
+#[derive(Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Dependency {
+    Db,
+    Smtp,
+}
+
+#[derive(Deserialize)]
+struct RoleChange {
+    role: Role,
+}
+
+#[derive(Deserialize)]
+struct PasswordChange {
+    #[serde(default)]
+    current_password: String,
+    #[serde(default)]
+    password: String,
+}
 
 impl Service {
-    pub fn count(&self, req: &Request, _: &matchit::Params) -> Response {
+    pub fn count(&self, req: &Request, _: params::Count) -> HandlerResult {
         let count = if req.method() == Method::POST {
             self.state.count.fetch_add(1, Ordering::Relaxed) + 1
         } else {
@@ -18,81 +62,974 @@ impl Service {
         let html = html! {
             (count)
         };
-        ResponseBuilder::new().body_html(html)
+        Ok(ResponseBuilder::new().body_html(html))
     }
 
     /// GET '/'
-    pub fn home(&self, _: &Request, _: &matchit::Params) -> Response {
+    pub fn home(&self, req: &Request, _: params::Home) -> HandlerResult {
+        let user = self.current_user(req)?;
         let html = fragment::page(
             "home",
+            RequestExt(req).csp_nonce(),
             html! {
                 article {
                     h2 { "An htmx button" }
                     p {
-                        button name="foo" hx-post="/count" hx-swap="innerHTML" {
+                        button name="foo" hx-post=(Route::count()) hx-swap="innerHTML" {
                             (self.state.count.load(Ordering::Relaxed))
                         }
                     }
                 }
 
-                (fragment::post("post-123", "A blogpost", "Lorem ipsum, something something."))
+                (fragment::post(
+                    "post-123",
+                    "A blogpost",
+                    "Lorem ipsum, something something.",
+                    user.is_some_and(|user| user.has_role(Role::Editor)),
+                ))
+
+                (fragment::saved_posts_sidebar(self.state.saved_posts.load(Ordering::Relaxed)))
+
+                (fragment::account(user))
             },
         );
-        ResponseBuilder::new().body_html(html)
+        // No `ETag`: a cached page would carry stale CSP nonces
+        Ok(ResponseBuilder::new().body_html(html))
     }
 
-    pub fn not_found_404(&self, _: &Request) -> Response {
+    pub fn not_found_404(&self, req: &Request) -> Response {
+        let message = AppError::NotFound.public_message();
+        if RequestExt(req).is_htmx() {
+            return self.error_response(req, StatusCode::NOT_FOUND, message);
+        }
+
         let html = fragment::page(
             "PAGE NOT FOUND",
+            RequestExt(req).csp_nonce(),
             html! {
-                h2 { "This page does not seem to exist, sorry!" }
+                h2 { (message) }
                 p {
-                    a href="/" { "Return to the main page" }
+                    a href=(Route::home()) { "Return to the main page" }
                 }
+                (fragment::request_id(RequestExt(req).request_id()))
             },
         );
 
         ResponseBuilder::new().status_not_found().body_html(html)
     }
 
-    pub fn too_many_requests_429(&self, _: &Request) -> Response {
-        ResponseBuilder::new()
+    /// Sends htmx requests to the login page, as the fragment they asked for
+    /// is no use; full page loads get a page linking to it
+    pub fn unauthorized_401(&self, req: &Request) -> Response {
+        let message = AppError::Unauthorized.public_message();
+        let builder = ResponseBuilder::new()
             .cache_nostore()
-            .status(StatusCode::TOO_MANY_REQUESTS)
-            .body_static_str("text/plain", "Too Many Requests")
+            .status(StatusCode::UNAUTHORIZED);
+        if RequestExt(req).is_htmx() {
+            return builder
+                .header("HX-Redirect", Route::login().to_string())
+                .body_html(message);
+        }
+
+        let html = fragment::page(
+            "Unauthorized",
+            RequestExt(req).csp_nonce(),
+            html! {
+                h2 { (message) }
+                p {
+                    a href=(Route::login()) { "Log in" }
+                }
+            },
+        );
+        builder.body_html(html)
+    }
+
+    /// Like [`Self::error_response`], for when something went wrong on our side
+    pub fn internal_server_error_500(&self, req: &Request) -> Response {
+        self.error_response(
+            req,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            error::INTERNAL_ERROR_MESSAGE,
+        )
+    }
+
+    /// Log an error returned by a handler and render it for the client
+    pub fn app_error(&self, req: &Request, err: AppError) -> Response {
+        match &err {
+            AppError::Internal(err) => error!(err = format!("{err:#}"), "Handler failed"),
+            _ => debug!(%err, "Handler returned an error"),
+        }
+
+        match err {
+            AppError::NotFound => self.not_found_404(req),
+            AppError::Unauthorized => self.unauthorized_401(req),
+            err => self.error_response(req, err.status(), err.public_message()),
+        }
     }
 
-    pub fn favicon_ico(&self, _: &Request, _: &matchit::Params) -> Response {
+    /// A full error page, or for htmx requests just the message, shown in the
+    /// `#flash` area (see `static/htmx-errors.js`)
+    pub fn error_response(&self, req: &Request, status: StatusCode, message: &str) -> Response {
+        let request_id = RequestExt(req).request_id();
+        let builder = ResponseBuilder::new().cache_nostore().status(status);
+
+        if RequestExt(req).is_htmx() {
+            return builder
+                .header("HX-Retarget", "#flash")
+                .header("HX-Reswap", "innerHTML")
+                .body_html(fragment::error_flash(message, request_id));
+        }
+
+        let html = fragment::page(
+            status.canonical_reason().unwrap_or("Error"),
+            RequestExt(req).csp_nonce(),
+            html! {
+                h2 { (message) }
+                p {
+                    a href=(Route::home()) { "Return to the main page" }
+                }
+                (fragment::request_id(request_id))
+            },
+        );
+        builder.body_html(html)
+    }
+
+    pub fn service_unavailable_503(&self, _: &Request) -> Response {
         ResponseBuilder::new()
-            .cache_static()
-            .body_static_bytes("image/gif", include_bytes!("../static/dpc.gif").as_slice())
+            .cache_nostore()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header("Connection", "close")
+            .header("Retry-After", "5")
+            .body_static_bytes("text/plain", b"Shutting down, try again shortly\n")
     }
 
-    pub fn style_css(&self, _: &Request, _: &matchit::Params) -> Response {
+    pub fn too_many_requests_429(&self, req: &Request) -> Response {
+        let body = match RequestExt(req).request_id() {
+            Some(id) => format!("Too Many Requests\n\nRequest id: {id}\n"),
+            None => "Too Many Requests\n".to_owned(),
+        };
         ResponseBuilder::new()
-            .cache_static()
-            .body_static_str("text/css", include_str!("../static/style.css"))
+            .cache_nostore()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header("Content-Type", "text/plain")
+            .body(Body::new(body))
+            .unwrap()
+    }
+
+    pub fn favicon_ico(&self, req: &Request, _: params::FaviconIco) -> HandlerResult {
+        let asset = assets::get("dpc.gif").context("favicon asset missing")?;
+        Ok(ResponseBuilder::new().cache_static().body_asset(req, asset))
+    }
+
+    /// GET '/static/:hash/*path'
+    pub fn static_asset(&self, req: &Request, params: params::StaticAsset) -> HandlerResult {
+        let params::StaticAsset { hash, path } = &params;
+
+        if dev::enabled() && hash == assets::DEV_HASH {
+            let content = dev::read_static(path).or_not_found()?;
+            return Ok(ResponseBuilder::new()
+                .cache_nostore()
+                .header("Content-Type", assets::content_type(path))
+                .body(Body::new(content))?);
+        }
+
+        // An outdated hash means a stale page; don't serve new content under an
+        // immutable URL
+        let asset = assets::get(path)
+            .filter(|asset| asset.hash == hash)
+            .or_not_found()?;
+
+        Ok(ResponseBuilder::new()
+            .cache_immutable()
+            .body_asset(req, asset))
+    }
+
+    /// GET '/dev/reload'
+    pub fn dev_reload(&self, _: &Request, _: params::DevReload) -> HandlerResult {
+        if !dev::enabled() {
+            return Err(AppError::NotFound);
+        }
+
+        Ok(ResponseBuilder::new()
+            .cache_nostore()
+            .header("Content-Type", "text/event-stream")
+            .body(Body::wrap_reader(dev::ReloadEvents::new()))?)
+    }
+
+    /// GET '/metrics'
+    pub fn metrics(&self, req: &Request, _: params::Metrics) -> HandlerResult {
+        let peer_ip = RequestExt(req).peer_addr().map(|addr| addr.ip());
+        if !metrics::access_allowed(&self.metrics_config, peer_ip, req.headers()) {
+            return Err(AppError::Forbidden);
+        }
+
+        Ok(ResponseBuilder::new()
+            .cache_nostore()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(Body::new(metrics::get().render()))?)
+    }
+
+    /// GET '/healthz': the process is alive and serving requests
+    pub fn healthz(&self, _: &Request, _: params::Healthz) -> HandlerResult {
+        Ok(ResponseBuilder::new()
+            .cache_nostore()
+            .body_static_bytes("text/plain", b"ok\n"))
+    }
+
+    /// GET '/readyz?check=<dependency>': all (or the given) dependencies are
+    /// usable
+    ///
+    /// Why a check failed is only logged.
+    pub fn readyz(&self, req: &Request, _: params::Readyz) -> HandlerResult {
+        let query: ReadyzQuery = RequestExt(req).query()?;
+        let wanted = |dependency| query.check.is_empty() || query.check.contains(&dependency);
+
+        let mut ready = true;
+        let mut checks = serde_json::Map::new();
+        let mut check = |name: &str, ok: bool| {
+            ready &= ok;
+            checks.insert(name.to_owned(), if ok { "ok" } else { "failed" }.into());
+        };
+
+        if wanted(Dependency::Db) {
+            check("db", self.db_check.check(&self.db));
+        }
+        if wanted(Dependency::Smtp) {
+            match &self.smtp_check {
+                Some(smtp_check) => check("smtp", smtp_check.check()),
+                None => {
+                    checks.insert("smtp".to_owned(), "not configured".into());
+                }
+            }
+        }
+
+        let body = serde_json::json!({
+            "status": if ready { "ready" } else { "not ready" },
+            "checks": checks,
+        });
+        Ok(ResponseBuilder::new()
+            .cache_nostore()
+            .status(if ready {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            })
+            .body_json(&body)?)
+    }
+
+    /// GET '/version'
+    pub fn version(&self, _: &Request, _: params::Version) -> HandlerResult {
+        Ok(ResponseBuilder::new()
+            .cache_nostore()
+            .body_json(&health::build_info())?)
     }
 
     /// GET '/user/:id'
-    pub fn get_user(&self, _: &Request, params: &matchit::Params) -> Response {
-        // Retrieve route parameters from the the request extensions
-        let id = params.get("id").unwrap();
+    pub fn get_user(&self, req: &Request, params: params::User) -> HandlerResult {
+        let user = auth::find_user_by_id(&*self.db.get()?, params.id)?.or_not_found()?;
+        let can_set_role = self
+            .current_user(req)?
+            .is_some_and(|current| current.has_role(Role::Admin));
+        let html = fragment::page(
+            &user.username,
+            RequestExt(req).csp_nonce(),
+            fragment::user_profile(params.id, &user, can_set_role),
+        );
+        Ok(ResponseBuilder::new().cache_nostore().body_html(html))
+    }
+
+    /// POST '/user/:id/role'
+    pub fn set_user_role(&self, req: &Request, params: params::UserRole) -> HandlerResult {
+        let form: RoleChange = RequestExt(req).form()?;
+        let db = self.db.get()?;
+        auth::find_user_by_id(&db, params.id)?.or_not_found()?;
+        auth::set_role_by_id(&db, params.id, form.role)?;
 
-        ResponseBuilder::new().body_html(html! { p { "User #"(id)  } })
+        Ok(self
+            .redirect(req, Route::user(params.id))
+            .body(Body::empty())?)
     }
 
-    pub fn edit_post(&self, _: &Request, params: &matchit::Params) -> Response {
-        // Retrieve route parameters from the the request extensions
-        let id = params.get("id").unwrap();
+    /// GET '/post/:id/edit'
+    pub fn edit_post(&self, _: &Request, params: params::PostEdit) -> HandlerResult {
+        Ok(
+            ResponseBuilder::new()
+                .body_html(fragment::post_edit_form(&params.id, "Foo", "Content")),
+        )
+    }
+
+    /// POST '/post/:id'
+    pub fn save_post(&self, _: &Request, params: params::Post) -> HandlerResult {
+        let id = &params.id;
+
+        let saved_posts = self.state.saved_posts.fetch_add(1, Ordering::Relaxed) + 1;
+
+        let resp = OobResponse::new(
+            fragment::post(id, "Foo", "Content", true),
+            [
+                (OobSwap::InnerHtml, "#flash", fragment::flash("Post saved")),
+                (
+                    OobSwap::InnerHtml,
+                    "#saved-posts-count",
+                    html! { (saved_posts) },
+                ),
+            ],
+        )?;
+
+        Ok(ResponseBuilder::new().body_html(resp))
+    }
 
-        ResponseBuilder::new().body_html(fragment::post_edit_form(id, "Foo", "Content"))
+    /// GET '/register'
+    pub fn register_page(&self, req: &Request, _: params::Register) -> HandlerResult {
+        Ok(self.form_page(req, "Register", fragment::register_form("", &[], html! {})))
     }
 
-    pub fn save_post(&self, _: &Request, params: &matchit::Params) -> Response {
-        // Retrieve route parameters from the the request extensions
-        let id = params.get("id").unwrap();
+    /// POST '/register'
+    pub fn register(&self, req: &Request, _: params::Register) -> HandlerResult {
+        let form: Credentials = RequestExt(req).form()?;
+        let username = auth::normalize_username(&form.username);
+
+        let mut errors = vec![];
+        if let Some(err) = self.username_error(&username)? {
+            errors.push(("username", err));
+        }
+        if let Err(err) = auth::validate_password(&form.password) {
+            errors.push(("password", err));
+        }
+        if errors.is_empty() {
+            match auth::create_user(&*self.db.get()?, &username, &form.password)? {
+                Some(user_id) => return self.start_session(req, user_id),
+                // Registered by someone else just now
+                None => errors.push(("username", USERNAME_TAKEN.to_owned())),
+            }
+        }
 
-        ResponseBuilder::new().body_html(fragment::post(id, "Foo", "Content"))
+        Ok(self.form_response(
+            req,
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Register",
+            fragment::REGISTER_FORM,
+            fragment::register_form(&username, &errors, html! {}),
+        ))
     }
+
+    /// GET '/login'
+    pub fn login_page(&self, req: &Request, _: params::Login) -> HandlerResult {
+        Ok(self.form_page(req, "Log in", fragment::login_form("", html! {})))
+    }
+
+    /// POST '/login'
+    pub fn login(&self, req: &Request, _: params::Login) -> HandlerResult {
+        let form: Credentials = RequestExt(req).form()?;
+        let username = auth::normalize_username(&form.username);
+
+        // Per account, so guessing a password takes forever even from many IPs
+        if self.login_rate_limiter.rate_limit(username.as_str()) {
+            metrics::get().inc_rate_limited();
+            return Ok(self.form_response(
+                req,
+                StatusCode::TOO_MANY_REQUESTS,
+                "Log in",
+                fragment::LOGIN_FORM,
+                fragment::login_form(
+                    &username,
+                    fragment::form_error("Too many login attempts, try again later."),
+                ),
+            ));
+        }
+
+        match auth::check_login(&*self.db.get()?, &username, &form.password)? {
+            Some(user_id) => self.start_session(req, user_id),
+            None => Ok(self.form_response(
+                req,
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Log in",
+                fragment::LOGIN_FORM,
+                fragment::login_form(
+                    &username,
+                    fragment::form_error("Wrong username or password."),
+                ),
+            )),
+        }
+    }
+
+    /// POST '/logout'
+    pub fn logout(&self, req: &Request, _: params::Logout) -> HandlerResult {
+        if let Some(token) = RequestExt(req).session_id() {
+            auth::delete_session(&*self.db.get()?, token)?;
+        }
+        Ok(self
+            .redirect(req, Route::home())
+            .header(
+                header::SET_COOKIE,
+                auth::session_cookie("", 0, self.secure_cookies),
+            )
+            .body(Body::empty())?)
+    }
+
+    /// GET '/account/password'
+    pub fn change_password_page(&self, req: &Request, _: params::ChangePassword) -> HandlerResult {
+        self.current_user(req)?.ok_or(AppError::Unauthorized)?;
+        Ok(self.form_page(
+            req,
+            "Change password",
+            fragment::change_password_form(&[], html! {}),
+        ))
+    }
+
+    /// POST '/account/password'
+    pub fn change_password(&self, req: &Request, _: params::ChangePassword) -> HandlerResult {
+        let user = self.current_user(req)?.ok_or(AppError::Unauthorized)?;
+        let form: PasswordChange = RequestExt(req).form()?;
+
+        let form_response = |status, errors: &[(&str, String)], notice| {
+            Ok(self.form_response(
+                req,
+                status,
+                "Change password",
+                fragment::CHANGE_PASSWORD_FORM,
+                fragment::change_password_form(errors, notice),
+            ))
+        };
+
+        // Counts as a login attempt, or a stolen session could guess the
+        // password just as well
+        if self.login_rate_limiter.rate_limit(user.username.as_str()) {
+            metrics::get().inc_rate_limited();
+            return form_response(
+                StatusCode::TOO_MANY_REQUESTS,
+                &[],
+                fragment::form_error("Too many attempts, try again later."),
+            );
+        }
+
+        let mut errors = vec![];
+        if auth::check_login(&*self.db.get()?, &user.username, &form.current_password)?.is_none() {
+            errors.push(("current_password", "Wrong password".to_owned()));
+        }
+        if let Err(err) = auth::validate_password(&form.password) {
+            errors.push(("password", err));
+        }
+        if !errors.is_empty() {
+            return form_response(StatusCode::UNPROCESSABLE_ENTITY, &errors, html! {});
+        }
+
+        // Ends all sessions, including this one, which is replaced right away
+        auth::set_password(&*self.db.get()?, user.id, &form.password)?;
+        let token = auth::create_session(&*self.db.get()?, user.id)?;
+        let mut resp = form_response(StatusCode::OK, &[], fragment::flash("Password changed."))?;
+        resp.headers_mut().insert(
+            header::SET_COOKIE,
+            HeaderValue::from_str(&auth::session_cookie(
+                &token,
+                auth::SESSION_MAX_AGE_SECS,
+                self.secure_cookies,
+            ))?,
+        );
+        Ok(resp)
+    }
+
+    /// POST '/account/validate': check the form field htmx names in
+    /// `HX-Trigger-Name` as it's typed in, responding with the error, if any
+    pub fn validate_field(&self, req: &Request, _: params::ValidateField) -> HandlerResult {
+        let form: Credentials = RequestExt(req).form()?;
+        let field = req
+            .headers()
+            .get("HX-Trigger-Name")
+            .and_then(|v| v.to_str().ok());
+        let error = match field {
+            Some("username") => self.username_error(&auth::normalize_username(&form.username))?,
+            Some("password") => auth::validate_password(&form.password).err(),
+            _ => return Err(AppError::BadRequest("Unknown form field".to_owned())),
+        };
+
+        Ok(ResponseBuilder::new()
+            .cache_nostore()
+            .body_html(html! { @if let Some(error) = error { (error) } }))
+    }
+
+    /// Why a (normalized) username can't be registered, if it can't
+    fn username_error(&self, username: &str) -> anyhow::Result<Option<String>> {
+        if let Err(err) = auth::validate_username(username) {
+            return Ok(Some(err));
+        }
+        Ok(auth::find_user(&*self.db.get()?, username)?.map(|_| USERNAME_TAKEN.to_owned()))
+    }
+
+    /// Log the user in, and send them to the home page
+    fn start_session(&self, req: &Request, user_id: u64) -> HandlerResult {
+        let token = auth::create_session(&*self.db.get()?, user_id)?;
+        Ok(self
+            .redirect(req, Route::home())
+            .header(
+                header::SET_COOKIE,
+                auth::session_cookie(&token, auth::SESSION_MAX_AGE_SECS, self.secure_cookies),
+            )
+            .body(Body::empty())?)
+    }
+
+    /// Send the browser to `to`: with `HX-Redirect` for htmx requests, which
+    /// would swap the page into the form otherwise, or a `303`
+    fn redirect(&self, req: &Request, to: Route) -> ResponseBuilder {
+        let builder = ResponseBuilder::new().cache_nostore();
+        if RequestExt(req).is_htmx() {
+            builder.header("HX-Redirect", to.to_string())
+        } else {
+            builder
+                .status(StatusCode::SEE_OTHER)
+                .header(header::LOCATION, to.to_string())
+        }
+    }
+
+    fn form_page(&self, req: &Request, title: &str, form: Markup) -> Response {
+        let html = fragment::page(
+            title,
+            RequestExt(req).csp_nonce(),
+            html! {
+                h2 { (title) }
+                (form)
+            },
+        );
+        ResponseBuilder::new().cache_nostore().body_html(html)
+    }
+
+    /// A submitted form, again: for htmx just the form, swapped in place of the
+    /// submitted one (see `static/htmx-errors.js`), otherwise the whole page
+    fn form_response(
+        &self,
+        req: &Request,
+        status: StatusCode,
+        title: &str,
+        form_id: &str,
+        form: Markup,
+    ) -> Response {
+        if RequestExt(req).is_htmx() {
+            return ResponseBuilder::new()
+                .cache_nostore()
+                .status(status)
+                .header("HX-Retarget", format!("#{form_id}"))
+                .header("HX-Reswap", "outerHTML")
+                .body_html(form);
+        }
+        let mut resp = self.form_page(req, title, form);
+        *resp.status_mut() = status;
+        resp
+    }
+}
+
+#[test]
+fn pages_test() {
+    use crate::test_client::TestClient;
+
+    let client = TestClient::new();
+
+    let resp = client.get("/").send();
+    resp.assert_status(StatusCode::OK);
+    assert_eq!(resp.select("button[hx-post='/count']"), ["0"]);
+    assert_eq!(resp.select("#saved-posts-count"), ["0"]);
+    // Only editors get to edit posts
+    assert_eq!(resp.select("#post-123 button").len(), 0);
+    // Sessions only start by logging in
+    assert_eq!(resp.cookie("session"), None);
+    assert_eq!(resp.select(".account a"), ["Log in", "register"]);
+    let resp = client.get("/").cookie("session", "abc").send();
+    resp.assert_status(StatusCode::OK);
+    assert_eq!(resp.cookie("session"), None);
+    assert!(resp.header("x-request-id").is_some());
+
+    // Every script carries the nonce from the CSP
+    let csp = resp.header("Content-Security-Policy").unwrap();
+    let scripts = resp.select_attr("script", "nonce");
+    assert_eq!(scripts.len(), resp.select("script").len());
+    assert!(scripts
+        .iter()
+        .all(|nonce| csp.contains(&format!("'nonce-{nonce}'"))));
+    assert_eq!(resp.select_attr("[style]", "style"), Vec::<String>::new());
+
+    let (editor, _) = client.log_in("editor", Role::Editor);
+    let resp = client.get("/").cookie("session", &editor).send();
+    assert_eq!(
+        resp.select_attr("#post-123 button", "hx-get"),
+        ["/post/post-123/edit"]
+    );
+
+    // Users see their own profile, admins everyone's
+    let (viewer, viewer_id) = client.log_in("viewer", Role::Viewer);
+    let (admin, _) = client.log_in("admin", Role::Admin);
+    let profile = format!("/user/{viewer_id}");
+    let resp = client.get(&profile).send();
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    assert_eq!(resp.select_attr("main a", "href"), ["/login"]);
+    for session in [&viewer, &admin] {
+        let resp = client.get(&profile).cookie("session", session).send();
+        resp.assert_status(StatusCode::OK);
+        assert_eq!(resp.select("h2"), ["viewer"]);
+    }
+    client
+        .get(&profile)
+        .cookie("session", &editor)
+        .send()
+        .assert_status(StatusCode::FORBIDDEN);
+    client
+        .get("/user/foo")
+        .cookie("session", &admin)
+        .send()
+        .assert_status(StatusCode::BAD_REQUEST);
+
+    // Only admins get to change roles
+    let set_role = |session: &str, role: &str| {
+        client
+            .post(&format!("/user/{viewer_id}/role"))
+            .cookie("session", session)
+            .form(&[("role", role)])
+            .send()
+    };
+    set_role(&editor, "admin").assert_status(StatusCode::FORBIDDEN);
+    let resp = client.get(&profile).cookie("session", &viewer).send();
+    assert_eq!(resp.select("select[name='role']").len(), 0);
+    let resp = client.get(&profile).cookie("session", &admin).send();
+    assert_eq!(
+        resp.select("select[name='role'] option[selected]"),
+        ["viewer"]
+    );
+    set_role(&admin, "nobody").assert_status(StatusCode::BAD_REQUEST);
+    let resp = set_role(&admin, "editor");
+    resp.assert_status(StatusCode::SEE_OTHER);
+    assert_eq!(resp.header("Location"), Some(profile.as_str()));
+    let resp = client.get("/").cookie("session", &viewer).send();
+    assert_eq!(
+        resp.select_attr("#post-123 button", "hx-get"),
+        ["/post/post-123/edit"]
+    );
+    client
+        .get("/user/12345")
+        .cookie("session", &admin)
+        .send()
+        .assert_status(StatusCode::NOT_FOUND);
+
+    let resp = client.get("/nope").send();
+    resp.assert_status(StatusCode::NOT_FOUND);
+    assert_eq!(
+        resp.select("main h2"),
+        [AppError::NotFound.public_message()]
+    );
+    assert_eq!(resp.select("p.request-id").len(), 1);
+    // Unsupported methods on known routes, too
+    client.post("/").send().assert_status(StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn htmx_interactions_test() {
+    use crate::test_client::TestClient;
+
+    let client = TestClient::new();
+
+    let resp = client.post("/count").htmx().form(&[("foo", "")]).send();
+    resp.assert_status(StatusCode::OK);
+    assert_eq!(resp.text(), "1");
+    assert_eq!(client.post("/count").htmx().send().text(), "2");
+    client
+        .get("/count")
+        .send()
+        .assert_status(StatusCode::NOT_FOUND);
+
+    // Editing takes logging in, as an editor
+    let resp = client.post("/post/7").htmx().send();
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    assert_eq!(resp.header("HX-Redirect"), Some("/login"));
+    let (viewer, _) = client.log_in("viewer", Role::Viewer);
+    let resp = client
+        .post("/post/7")
+        .htmx()
+        .cookie("session", &viewer)
+        .send();
+    resp.assert_status(StatusCode::FORBIDDEN);
+    assert_eq!(resp.header("HX-Retarget"), Some("#flash"));
+    assert_eq!(
+        resp.select(".flash.error p:first-child"),
+        [AppError::Forbidden.public_message()]
+    );
+
+    let (editor, _) = client.log_in("editor", Role::Editor);
+    let resp = client
+        .get("/post/7/edit")
+        .htmx()
+        .cookie("session", &editor)
+        .send();
+    resp.assert_status(StatusCode::OK);
+    assert_eq!(
+        resp.select_attr("article[id='7'] button", "hx-post"),
+        ["/post/7"]
+    );
+
+    let resp = client
+        .post("/post/7")
+        .htmx()
+        .cookie("session", &editor)
+        .form(&[("title", "Foo"), ("content", "Content")])
+        .send();
+    resp.assert_status(StatusCode::OK);
+    assert_eq!(resp.select("article.post h2"), ["Foo"]);
+    assert_eq!(
+        resp.select("[hx-swap-oob='innerHTML:#flash']"),
+        ["Post saved"]
+    );
+    assert_eq!(
+        resp.select("[hx-swap-oob='innerHTML:#saved-posts-count']"),
+        ["1"]
+    );
+
+    // The page reflects what was done through htmx
+    let resp = client.get("/").send();
+    assert_eq!(resp.select("button[hx-post='/count']"), ["2"]);
+    assert_eq!(resp.select("#saved-posts-count"), ["1"]);
+
+    // Errors are retargeted into the flash area
+    let resp = client.get("/nope").htmx().send();
+    resp.assert_status(StatusCode::NOT_FOUND);
+    assert_eq!(resp.header("HX-Retarget"), Some("#flash"));
+    assert_eq!(resp.select("h2"), Vec::<String>::new());
+    assert_eq!(
+        resp.select(".flash.error p:first-child"),
+        [AppError::NotFound.public_message()]
+    );
+}
+
+#[test]
+fn assets_test() {
+    use crate::htmx;
+    use crate::test_client::TestClient;
+
+    let client = TestClient::new();
+
+    let resp = client.get("/favicon.ico").send();
+    resp.assert_status(StatusCode::OK);
+    assert_eq!(resp.body, assets::get("dpc.gif").unwrap().content);
+
+    let resp = client.get(&assets::url("style.css")).send();
+    resp.assert_status(StatusCode::OK);
+    assert_eq!(resp.header("Content-Type"), Some("text/css"));
+    client
+        .get("/static/0000/style.css")
+        .send()
+        .assert_status(StatusCode::NOT_FOUND);
+
+    let resp = client.get(&htmx::url()).send();
+    resp.assert_status(StatusCode::OK);
+    assert_eq!(resp.body, htmx::asset().content);
+
+    // Each encoding is validated on its own
+    let get = |accept_encoding: &str, if_none_match: &str| {
+        client
+            .get(&htmx::url())
+            .header("Accept-Encoding", accept_encoding)
+            .header("If-None-Match", if_none_match)
+            .send()
+    };
+    let resp = get("br", "\"none\"");
+    resp.assert_status(StatusCode::OK);
+    let etag = resp.header("ETag").unwrap().to_owned();
+    assert!(etag.ends_with("-br\""));
+    let resp = get("br", &etag);
+    resp.assert_status(StatusCode::NOT_MODIFIED);
+    assert_eq!(resp.header("Vary"), Some("Accept-Encoding"));
+    assert_eq!(resp.header("ETag"), Some(etag.as_str()));
+    get("gzip", &etag).assert_status(StatusCode::OK);
+    let resp = client.get("/").send();
+    assert_eq!(
+        resp.select_attr("script[src$='/htmx.min.js']", "integrity"),
+        [htmx::integrity()]
+    );
+
+    // Only served in dev mode
+    client
+        .get("/dev/reload")
+        .send()
+        .assert_status(StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn operations_test() {
+    use crate::test_client::TestClient;
+
+    let client = TestClient::new();
+
+    let resp = client.get("/healthz").send();
+    resp.assert_status(StatusCode::OK);
+    assert_eq!(resp.text(), "ok\n");
+
+    let resp = client.get("/readyz").send();
+    resp.assert_status(StatusCode::OK);
+    assert_eq!(resp.json()["checks"]["db"], "ok");
+    assert_eq!(resp.json()["checks"]["smtp"], "not configured");
+    let resp = client.get("/readyz?check=db").send();
+    resp.assert_status(StatusCode::OK);
+    assert_eq!(resp.json()["checks"], serde_json::json!({ "db": "ok" }));
+    let resp = client.get("/readyz?check=db&check=smtp").send();
+    assert_eq!(resp.json()["checks"]["smtp"], "not configured");
+    let resp = client.get("/readyz?check=disk").htmx().send();
+    resp.assert_status(StatusCode::BAD_REQUEST);
+    assert_eq!(resp.header("HX-Retarget"), Some("#flash"));
+    assert!(resp.text().contains("Invalid query string"));
+
+    let resp = client.get("/version").send();
+    resp.assert_status(StatusCode::OK);
+    assert_eq!(resp.json()["version"], env!("CARGO_PKG_VERSION"));
+
+    let resp = client.get("/metrics").send();
+    resp.assert_status(StatusCode::OK);
+    assert!(resp
+        .text()
+        .contains("http_requests_total{route=\"/version\""));
+    client
+        .get("/metrics")
+        .peer_addr(Some(([10, 0, 0, 1], 40000).into()))
+        .send()
+        .assert_status(StatusCode::FORBIDDEN);
+}
+
+#[test]
+fn rate_limiting_test() {
+    use crate::test_client::TestClient;
+
+    let client = TestClient::with_config(|config| {
+        config.rate_limit.pre.threshold = 1;
+        config.rate_limit.conventional.threshold = 1;
+        config.client_ip_header = Some("X-Forwarded-For".to_owned());
+    });
+    // Behind a proxy on a unix socket, clients are told apart by the headers
+    // it sets
+    let get = |ip: &str| {
+        client
+            .get("/")
+            .peer_addr(None)
+            .header("X-Forwarded-For", &format!("203.0.113.1, {ip}"))
+            .send()
+            .status
+    };
+
+    let limited_after = (0..100).position(|_| get("10.0.0.1") == StatusCode::TOO_MANY_REQUESTS);
+    assert!(limited_after.is_some());
+    assert_eq!(get("10.0.0.2"), StatusCode::OK);
+    // Probes are never limited
+    client
+        .get("/healthz")
+        .peer_addr(None)
+        .header("X-Forwarded-For", "10.0.0.1")
+        .send()
+        .assert_status(StatusCode::OK);
+}
+
+#[test]
+fn auth_flow_test() {
+    use crate::test_client::TestClient;
+
+    let client = TestClient::with_config(|config| config.rate_limit.login.threshold = 4);
+
+    // Inline validation, of the field named by htmx
+    let validate = |field: &str, fields: &[(&str, &str)]| {
+        let resp = client
+            .post("/account/validate")
+            .htmx()
+            .header("HX-Trigger-Name", field)
+            .form(fields)
+            .send();
+        resp.assert_status(StatusCode::OK);
+        resp.text()
+    };
+    assert_ne!(validate("username", &[("username", "a")]), "");
+    assert_eq!(validate("username", &[("username", "Alice")]), "");
+    assert_ne!(validate("password", &[("password", "short")]), "");
+    assert_eq!(validate("password", &[("password", "long enough")]), "");
+
+    let resp = client.get("/register").send();
+    resp.assert_status(StatusCode::OK);
+    assert_eq!(
+        resp.select_attr("#register-form input[hx-post]", "name"),
+        ["username", "password"]
+    );
+
+    let resp = client
+        .post("/register")
+        .htmx()
+        .form(&[("username", "a"), ("password", "short")])
+        .send();
+    resp.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(resp.header("HX-Retarget"), Some("#register-form"));
+    assert_eq!(resp.select(".field-error").len(), 2);
+    assert!(resp.select(".field-error").iter().all(|e| !e.is_empty()));
+
+    let resp = client
+        .post("/register")
+        .htmx()
+        .form(&[("username", "Alice"), ("password", "hunter22")])
+        .send();
+    resp.assert_status(StatusCode::OK);
+    assert_eq!(resp.header("HX-Redirect"), Some("/"));
+    let session = resp.cookie("session").unwrap().to_owned();
+    assert!(resp
+        .header("Set-Cookie")
+        .unwrap()
+        .contains("HttpOnly; SameSite=Lax"));
+    assert_eq!(
+        validate("username", &[("username", "alice")]),
+        USERNAME_TAKEN
+    );
+
+    let resp = client.get("/").cookie("session", &session).send();
+    assert_eq!(resp.select(".account strong"), ["alice"]);
+
+    client
+        .get("/account/password")
+        .send()
+        .assert_status(StatusCode::UNAUTHORIZED);
+    let change_password = |session: &str, current: &str| {
+        client
+            .post("/account/password")
+            .htmx()
+            .cookie("session", session)
+            .form(&[("current_password", current), ("password", "correct horse")])
+            .send()
+    };
+    let resp = change_password(&session, "wrong");
+    resp.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(resp.select(".field-error"), ["Wrong password", ""]);
+    let resp = change_password(&session, "hunter22");
+    resp.assert_status(StatusCode::OK);
+    assert_eq!(resp.select(".flash"), ["Password changed."]);
+    // Other sessions end, this one carries on with a new cookie
+    let new_session = resp.cookie("session").unwrap().to_owned();
+    let resp = client.get("/").cookie("session", &session).send();
+    assert_eq!(resp.select(".account strong"), Vec::<String>::new());
+
+    let resp = client
+        .post("/logout")
+        .htmx()
+        .cookie("session", &new_session)
+        .send();
+    assert_eq!(resp.header("HX-Redirect"), Some("/"));
+    assert_eq!(resp.cookie("session"), Some(""));
+    let resp = client.get("/").cookie("session", &new_session).send();
+    assert_eq!(resp.select(".account strong"), Vec::<String>::new());
+
+    // Without htmx, forms are answered with redirects and whole pages
+    let resp = client
+        .post("/login")
+        .form(&[("username", "ALICE"), ("password", "correct horse")])
+        .send();
+    resp.assert_status(StatusCode::SEE_OTHER);
+    assert_eq!(resp.header("Location"), Some("/"));
+    let resp = client
+        .post("/login")
+        .form(&[("username", "alice"), ("password", "wrong")])
+        .send();
+    resp.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(resp.select("main h2"), ["Log in"]);
+    // ... until the account's attempts are used up, wherever they come from
+    let resp = client
+        .post("/login")
+        .peer_addr(Some(([10, 0, 0, 1], 40000).into()))
+        .form(&[("username", "alice"), ("password", "correct horse")])
+        .send();
+    resp.assert_status(StatusCode::TOO_MANY_REQUESTS);
 }
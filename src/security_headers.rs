@@ -0,0 +1,90 @@
+//! Security headers, with a `Content-Security-Policy` nonce per request
+//!
+//! The nonce is put into request extensions, for [`crate::fragment::page`] to
+//! put on the `script` tags it emits, and for htmx to put on scripts it
+//! inserts (`htmx.config.inlineScriptNonce`). Since it differs on every
+//! request, so do the `ETag`s of pages.
+
+use std::fmt;
+
+use anyhow::Context;
+use base64::Engine;
+use hyper::http::HeaderValue;
+use hyper::{header, HeaderMap};
+
+use crate::config::SecurityHeadersConfig;
+
+/// Placeholder in [`SecurityHeadersConfig::content_security_policy`]
+pub const NONCE_PLACEHOLDER: &str = "{nonce}";
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CspNonce(String);
+
+impl CspNonce {
+    pub fn generate() -> anyhow::Result<Self> {
+        let mut bytes = [0u8; 16];
+        getrandom::getrandom(&mut bytes).context("Failed to generate a CSP nonce")?;
+        Ok(Self(
+            base64::engine::general_purpose::STANDARD.encode(bytes),
+        ))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for CspNonce {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Set the configured headers, leaving alone any the handler set itself
+pub fn apply(config: &SecurityHeadersConfig, nonce: Option<&CspNonce>, headers: &mut HeaderMap) {
+    let csp = match nonce {
+        Some(nonce) => config
+            .content_security_policy
+            .replace(NONCE_PLACEHOLDER, nonce.as_str()),
+        None => config.content_security_policy.clone(),
+    };
+
+    for (name, value) in [
+        (header::CONTENT_SECURITY_POLICY, csp.as_str()),
+        (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+        (header::REFERRER_POLICY, &config.referrer_policy),
+        (
+            header::HeaderName::from_static("permissions-policy"),
+            &config.permissions_policy,
+        ),
+        (header::X_FRAME_OPTIONS, &config.frame_options),
+    ] {
+        if value.is_empty() || headers.contains_key(&name) {
+            continue;
+        }
+        // Validated with the config; the nonce is base64
+        if let Ok(value) = HeaderValue::from_str(value) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+#[test]
+fn security_headers_test() {
+    let config = SecurityHeadersConfig::default();
+    let nonce = CspNonce::generate().unwrap();
+    assert_ne!(nonce, CspNonce::generate().unwrap());
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::X_FRAME_OPTIONS,
+        HeaderValue::from_static("SAMEORIGIN"),
+    );
+    apply(&config, Some(&nonce), &mut headers);
+
+    let csp = headers[header::CONTENT_SECURITY_POLICY].to_str().unwrap();
+    assert!(csp.contains(&format!("'nonce-{nonce}'")), "{csp}");
+    assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+    assert!(headers.contains_key("permissions-policy"));
+    assert_eq!(headers[header::X_FRAME_OPTIONS], "SAMEORIGIN");
+}
@@ -0,0 +1,366 @@
+//! Front end for all the listeners: TCP with or without TLS, unix domain
+//! sockets and sockets passed in by systemd
+//!
+//! Connections are accepted by tokio + hyper, and each request is handed to
+//! [`Service::handle`] on a blocking thread. Unlike astra, this can stop
+//! accepting connections on shutdown.
+
+use std::convert::Infallible;
+use std::fs::{self, Permissions};
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::os::fd::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use hyper::body::{Bytes, HttpBody};
+use hyper::service::service_fn;
+use hyper::StatusCode;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::runtime::Handle;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, warn};
+
+use crate::config::ListenAddr;
+use crate::shutdown::InFlight;
+use crate::Service;
+
+/// First file descriptor passed by systemd (`SD_LISTEN_FDS_START`)
+const SYSTEMD_FIRST_FD: RawFd = 3;
+
+/// Accepting fails e.g. when out of file descriptors, and would keep failing
+/// right away until some connections are closed
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// `socket_mode` sets the permissions of a unix socket, so e.g. only the
+    /// group of the proxy can connect
+    pub fn open(listen: &ListenAddr, socket_mode: Option<u32>) -> anyhow::Result<Self> {
+        Ok(match listen {
+            ListenAddr::Tcp(addr) => Listener::Tcp(
+                TcpListener::bind(addr).with_context(|| format!("Failed to listen on {addr}"))?,
+            ),
+            ListenAddr::Unix(path) => Listener::Unix(bind_unix(path, socket_mode)?),
+            ListenAddr::Systemd => from_systemd()?,
+        })
+    }
+
+    pub fn local_port(&self) -> Option<u16> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().ok().map(|addr| addr.port()),
+            Listener::Unix(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Listener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{addr}"),
+                Err(_) => f.write_str("tcp socket"),
+            },
+            Listener::Unix(listener) => match listener
+                .local_addr()
+                .ok()
+                .and_then(|addr| addr.as_pathname().map(Path::to_owned))
+            {
+                Some(path) => write!(f, "unix:{}", path.display()),
+                None => f.write_str("unix socket"),
+            },
+        }
+    }
+}
+
+fn bind_unix(path: &Path, socket_mode: Option<u32>) -> anyhow::Result<UnixListener> {
+    if socket_mode.is_none() {
+        warn!(
+            path = %path.display(),
+            "No socket_mode set, so any local user the umask allows can connect and pick their client IP"
+        );
+    }
+    // Left behind by a previous run; anything else is not ours to remove
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale socket {}", path.display()))?,
+        Ok(_) => bail!("{} exists and is not a socket", path.display()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err).with_context(|| format!("Failed to check {}", path.display())),
+    }
+
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to listen on {}", path.display()))?;
+    if let Some(mode) = socket_mode {
+        fs::set_permissions(path, Permissions::from_mode(mode))
+            .with_context(|| format!("Failed to set permissions of {}", path.display()))?;
+    }
+    Ok(listener)
+}
+
+/// The socket passed in by systemd socket activation
+fn from_systemd() -> anyhow::Result<Listener> {
+    let var = |name| std::env::var(name).ok().and_then(|v| v.parse::<u32>().ok());
+    if var("LISTEN_PID") != Some(std::process::id()) {
+        bail!("Not started by systemd socket activation (LISTEN_PID is not set to our pid)");
+    }
+    let fds = var("LISTEN_FDS").unwrap_or(0);
+    if fds == 0 {
+        bail!("systemd passed no sockets (LISTEN_FDS)");
+    }
+    if 1 < fds {
+        warn!(
+            fds,
+            "systemd passed more than one socket, using the first one"
+        );
+    }
+    // Not meant for child processes
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+
+    // SAFETY: systemd hands the fd over to us, and nothing else uses it
+    let listener = unsafe { UnixListener::from_raw_fd(SYSTEMD_FIRST_FD) };
+    // `local_addr` fails if it's not a unix socket
+    if listener.local_addr().is_ok() {
+        return Ok(Listener::Unix(listener));
+    }
+    // SAFETY: as above, just reinterpreted
+    let listener = unsafe { TcpListener::from_raw_fd(listener.into_raw_fd()) };
+    listener
+        .local_addr()
+        .context("The socket passed by systemd is neither a TCP nor a unix socket")?;
+    Ok(Listener::Tcp(listener))
+}
+
+/// Accept connections on `listener` until shutdown starts, doing a TLS
+/// handshake first if there is an `acceptor`
+///
+/// Connections already open keep being served until the process exits.
+pub fn serve(
+    listener: Listener,
+    acceptor: Option<TlsAcceptor>,
+    service: Service,
+) -> io::Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async move {
+        let shutdown = service.shutdown.clone();
+        let stopped = shutdown.requested();
+        tokio::pin!(stopped);
+        match listener {
+            Listener::Tcp(listener) => {
+                listener.set_nonblocking(true)?;
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+                loop {
+                    let (stream, peer_addr) = match tokio::select! {
+                        conn = listener.accept() => conn,
+                        () = &mut stopped => break,
+                    } {
+                        Ok(conn) => conn,
+                        Err(err) => {
+                            accept_failed(err).await;
+                            continue;
+                        }
+                    };
+                    tokio::spawn(serve_connection(
+                        stream,
+                        Some(peer_addr),
+                        acceptor.clone(),
+                        service.clone(),
+                    ));
+                }
+            }
+            Listener::Unix(listener) => {
+                listener.set_nonblocking(true)?;
+                let listener = tokio::net::UnixListener::from_std(listener)?;
+                loop {
+                    let stream = match tokio::select! {
+                        conn = listener.accept() => conn,
+                        () = &mut stopped => break,
+                    } {
+                        Ok((stream, _)) => stream,
+                        Err(err) => {
+                            accept_failed(err).await;
+                            continue;
+                        }
+                    };
+                    tokio::spawn(serve_connection(
+                        stream,
+                        None,
+                        acceptor.clone(),
+                        service.clone(),
+                    ));
+                }
+            }
+        }
+        // The listener is closed, but dropping the runtime would cut off
+        // requests in flight
+        debug!("Stopped accepting connections");
+        std::future::pending().await
+    })
+}
+
+async fn accept_failed(err: io::Error) {
+    error!(%err, "Failed to accept a connection");
+    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+}
+
+async fn serve_connection<S>(
+    stream: S,
+    peer_addr: Option<SocketAddr>,
+    acceptor: Option<TlsAcceptor>,
+    service: Service,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let handler = service_fn(move |req| handle(service.clone(), req, peer_addr));
+    let mut http = hyper::server::conn::Http::new();
+    http.http1_only(true);
+
+    let res = match acceptor {
+        Some(acceptor) => match acceptor.accept(stream).await {
+            Ok(stream) => http.serve_connection(stream, handler).await,
+            Err(err) => {
+                debug!(%err, "TLS handshake failed");
+                return;
+            }
+        },
+        None => http.serve_connection(stream, handler).await,
+    };
+    if let Err(err) = res {
+        debug!(%err, "Connection failed");
+    }
+}
+
+/// Bridge between hyper's async bodies and the blocking [`Service`]
+async fn handle(
+    service: Service,
+    req: hyper::Request<hyper::Body>,
+    peer_addr: Option<SocketAddr>,
+) -> Result<hyper::Response<hyper::Body>, Infallible> {
+    let (parts, body) = req.into_parts();
+    let body = BlockingBody {
+        body,
+        runtime: Handle::current(),
+        chunk: Bytes::new(),
+    };
+    let req = hyper::Request::from_parts(parts, astra::Body::wrap_reader(body));
+
+    let Ok(resp) = tokio::task::spawn_blocking(move || service.handle(req, peer_addr)).await else {
+        return Ok(plain_response(StatusCode::INTERNAL_SERVER_ERROR));
+    };
+
+    // astra bodies may block while producing chunks (e.g. server-sent events)
+    let (mut parts, body) = resp.into_parts();
+    let in_flight = parts.extensions.remove::<InFlight>();
+    let (mut sender, hyper_body) = hyper::Body::channel();
+    let runtime = Handle::current();
+    tokio::task::spawn_blocking(move || {
+        // Until the whole body is handed to hyper
+        let _in_flight = in_flight;
+        for chunk in body {
+            match chunk {
+                Ok(chunk) => {
+                    if runtime.block_on(sender.send_data(chunk)).is_err() {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    debug!(%err, "Failed to read response body");
+                    sender.abort();
+                    break;
+                }
+            }
+        }
+    });
+    Ok(hyper::Response::from_parts(parts, hyper_body))
+}
+
+/// A hyper body read from a blocking thread, like astra's, so it's only read
+/// if the handler needs it
+struct BlockingBody {
+    body: hyper::Body,
+    runtime: Handle,
+    /// Left over from the last read
+    chunk: Bytes,
+}
+
+impl io::Read for BlockingBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.runtime.block_on(self.body.data()) {
+                Some(Ok(chunk)) => self.chunk = chunk,
+                Some(Err(err)) => return Err(io::Error::other(err)),
+                None => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len());
+        buf[..len].copy_from_slice(&self.chunk.split_to(len));
+        Ok(len)
+    }
+}
+
+fn plain_response(status: StatusCode) -> hyper::Response<hyper::Body> {
+    let mut resp = hyper::Response::new(hyper::Body::from(
+        status.canonical_reason().unwrap_or_default(),
+    ));
+    *resp.status_mut() = status;
+    resp
+}
+
+#[test]
+fn unix_socket_test() {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+
+    use crate::test_client::TestClient;
+
+    let dir = std::env::temp_dir().join(format!("htmx-demo-unix-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("http.sock");
+    // A stale socket from a previous run gets replaced
+    drop(UnixListener::bind(&path).unwrap());
+
+    let listener = Listener::open(&ListenAddr::Unix(path.clone()), Some(0o600)).unwrap();
+    assert_eq!(
+        fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+        0o600
+    );
+    let client = TestClient::new();
+    {
+        let service = client.service().clone();
+        std::thread::spawn(move || serve(listener, None, service));
+    }
+
+    let send = |req: &str| {
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.write_all(req.as_bytes()).unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        resp
+    };
+    let resp = send("GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert!(resp.starts_with("HTTP/1.1 200"), "{resp}");
+    assert!(resp.ends_with("ok\n"), "{resp}");
+    // Bodies are read by handlers, from a blocking thread
+    let resp = send(
+        "POST /account/validate HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+         HX-Trigger-Name: password\r\nContent-Length: 14\r\n\r\npassword=short",
+    );
+    assert!(resp.starts_with("HTTP/1.1 200"), "{resp}");
+    assert!(!resp.ends_with("\r\n\r\n"), "{resp}");
+
+    fs::write(dir.join("file"), "").unwrap();
+    assert!(Listener::open(&ListenAddr::Unix(dir.join("file")), None).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
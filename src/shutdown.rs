@@ -0,0 +1,178 @@
+//! Graceful shutdown on SIGTERM/SIGINT
+//!
+//! Once shutdown starts, [`crate::server`] stops accepting connections, and
+//! requests on connections already open get a `503` with `Connection: close`,
+//! while the ones in flight get to finish, bodies included. Then the in-memory
+//! state is persisted and the database closed.
+
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::time::Duration;
+use std::{io, process, thread};
+
+use anyhow::Context;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::{persist, Service};
+
+pub struct Shutdown {
+    requested: watch::Sender<bool>,
+    in_flight: Mutex<usize>,
+    idle: Condvar,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            requested: watch::channel(false).0,
+            in_flight: Default::default(),
+            idle: Default::default(),
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn is_requested(&self) -> bool {
+        *self.requested.borrow()
+    }
+
+    /// Resolves once shutdown starts
+    pub async fn requested(&self) {
+        let _ = self
+            .requested
+            .subscribe()
+            .wait_for(|requested| *requested)
+            .await;
+    }
+
+    /// Count a request as in flight until the guard is dropped, unless
+    /// shutdown has already started
+    pub fn track(self: &Arc<Self>) -> Option<InFlight> {
+        // Checked under the lock, so `begin` can't miss a request
+        let mut in_flight = self.in_flight.lock().expect("locking failed");
+        if self.is_requested() {
+            return None;
+        }
+        *in_flight += 1;
+        Some(InFlight(self.clone()))
+    }
+
+    fn begin(&self) {
+        let _in_flight = self.in_flight.lock().expect("locking failed");
+        // Unlike `send`, works without receivers
+        self.requested.send_replace(true);
+    }
+
+    /// Returns `false` if requests were still in flight after `timeout`
+    fn wait_idle(&self, timeout: Duration) -> bool {
+        let in_flight = self.in_flight.lock().expect("locking failed");
+        let (in_flight, _) = self
+            .idle
+            .wait_timeout_while(in_flight, timeout, |n| 0 < *n)
+            .expect("locking failed");
+        *in_flight == 0
+    }
+}
+
+/// Kept in the response extensions, so it's only dropped once the body is
+/// written
+pub struct InFlight(Arc<Shutdown>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut in_flight = self.0.in_flight.lock().expect("locking failed");
+        *in_flight -= 1;
+        if *in_flight == 0 {
+            self.0.idle.notify_all();
+        }
+    }
+}
+
+enum Event {
+    Signal(i32),
+    ServerExited(io::Result<()>),
+}
+
+/// Run `serve` until SIGTERM/SIGINT, then drain in-flight requests of
+/// `service` for up to `timeout` and shut down
+///
+/// A second signal exits immediately.
+pub fn serve_until_signal(
+    serve: impl FnOnce() -> io::Result<()> + Send + 'static,
+    service: Service,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    let (tx, rx) = mpsc::channel();
+
+    let mut signals = Signals::new([SIGTERM, SIGINT]).context("Failed to set signal handlers")?;
+    {
+        let tx = tx.clone();
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                if tx.send(Event::Signal(signal)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    thread::spawn(move || {
+        let _ = tx.send(Event::ServerExited(serve()));
+    });
+
+    match rx.recv().context("Server thread is gone")? {
+        Event::ServerExited(res) => {
+            res.context("Failed to start http server")?;
+            return Ok(());
+        }
+        Event::Signal(signal) => {
+            info!(signal, timeout_secs = timeout.as_secs(), "Shutting down");
+        }
+    }
+
+    thread::spawn(move || {
+        while let Ok(event) = rx.recv() {
+            if let Event::Signal(signal) = event {
+                warn!(signal, "Signal received again, exiting immediately");
+                process::exit(1);
+            }
+        }
+    });
+
+    service.shutdown.begin();
+    if !service.shutdown.wait_idle(timeout) {
+        warn!("Requests still in flight after the shutdown timeout");
+    }
+
+    {
+        let db = service.db.get()?;
+        persist::save(
+            &db,
+            &service.state,
+            &service.pre_rate_limiter,
+            &service.rate_limiter,
+        )
+        .context("Failed to persist state on shutdown")?;
+    }
+    service.db.close();
+
+    info!("Shutdown complete");
+    Ok(())
+}
+
+#[test]
+fn shutdown_test() {
+    let shutdown = Arc::new(Shutdown::default());
+    let in_flight = shutdown.track().unwrap();
+    shutdown.begin();
+    assert!(shutdown.track().is_none());
+    assert!(!shutdown.wait_idle(Duration::from_millis(10)));
+
+    drop(in_flight);
+    assert!(shutdown.wait_idle(Duration::ZERO));
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+        .block_on(shutdown.requested());
+}
@@ -0,0 +1,216 @@
+//! In-process client for testing [`Service`] end to end, without a socket
+//!
+//! Each [`TestClient`] gets its own data directory (and so database) under
+//! the system temp dir, removed when the client is dropped.
+
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use hyper::http::request;
+use hyper::{header, HeaderMap, Method, StatusCode};
+use scraper::{Html, Selector};
+
+use crate::auth::{self, Role};
+use crate::config::Config;
+use crate::rate_limit::pre;
+use crate::Service;
+
+pub struct TestClient {
+    service: Service,
+    data_dir: PathBuf,
+}
+
+impl TestClient {
+    pub fn new() -> Self {
+        Self::with_config(|_| {})
+    }
+
+    /// Rate limits are lifted by default, so tests can send as many requests
+    /// as they like; `f` can tighten them again, or change anything else
+    pub fn with_config(f: impl FnOnce(&mut Config)) -> Self {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let data_dir = std::env::temp_dir().join(format!(
+            "htmx-demo-test-client-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut config = Config {
+            data_dir: data_dir.clone(),
+            ..Config::default()
+        };
+        config.rate_limit.pre.threshold = pre::FastPreRateLimiter::MAX_THRESHOLD;
+        config.rate_limit.conventional.threshold = 10_000;
+        f(&mut config);
+
+        Self {
+            service: Service::new(&config).expect("Failed to create service"),
+            data_dir,
+        }
+    }
+
+    pub fn service(&self) -> &Service {
+        &self.service
+    }
+
+    /// Create a user with `role`, and log it in; returns the session cookie
+    /// value and the user's id
+    pub fn log_in(&self, username: &str, role: Role) -> (String, u64) {
+        let db = self.service.db.get().unwrap();
+        let id = auth::create_user(&db, username, "test password")
+            .unwrap()
+            .expect("Username is taken");
+        auth::set_role(&db, username, role).unwrap();
+        (auth::create_session(&db, id).unwrap(), id)
+    }
+
+    pub fn get(&self, path: &str) -> TestRequest<'_> {
+        self.request(Method::GET, path)
+    }
+
+    pub fn post(&self, path: &str) -> TestRequest<'_> {
+        self.request(Method::POST, path)
+    }
+
+    pub fn request(&self, method: Method, path: &str) -> TestRequest<'_> {
+        TestRequest {
+            client: self,
+            builder: request::Builder::new().method(method).uri(path),
+            body: vec![],
+            peer_addr: Some((Ipv4Addr::LOCALHOST, 40000).into()),
+        }
+    }
+}
+
+impl Drop for TestClient {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.data_dir);
+    }
+}
+
+#[must_use]
+pub struct TestRequest<'c> {
+    client: &'c TestClient,
+    builder: request::Builder,
+    body: Vec<u8>,
+    peer_addr: Option<SocketAddr>,
+}
+
+impl TestRequest<'_> {
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.builder = self.builder.header(name, value);
+        self
+    }
+
+    pub fn cookie(self, name: &str, value: &str) -> Self {
+        self.header(header::COOKIE.as_str(), &format!("{name}={value}"))
+    }
+
+    /// Look like a request sent by htmx, rather than a full page load
+    pub fn htmx(self) -> Self {
+        self.header("HX-Request", "true")
+    }
+
+    /// An `application/x-www-form-urlencoded` body, like a submitted `<form>`
+    pub fn form(mut self, fields: &[(&str, &str)]) -> Self {
+        self.body = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(fields)
+            .finish()
+            .into_bytes();
+        self.header(
+            header::CONTENT_TYPE.as_str(),
+            "application/x-www-form-urlencoded",
+        )
+    }
+
+    pub fn peer_addr(mut self, peer_addr: Option<SocketAddr>) -> Self {
+        self.peer_addr = peer_addr;
+        self
+    }
+
+    pub fn send(self) -> TestResponse {
+        let req = self
+            .builder
+            .body(astra::Body::new(self.body))
+            .expect("Invalid test request");
+        let resp = self.client.service.handle(req, self.peer_addr);
+
+        let (parts, body) = resp.into_parts();
+        let mut content = vec![];
+        for chunk in body {
+            content.extend_from_slice(&chunk.expect("Failed to read response body"));
+        }
+        TestResponse {
+            status: parts.status,
+            headers: parts.headers,
+            body: content,
+        }
+    }
+}
+
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+
+    /// Value of a cookie set with `Set-Cookie`
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .filter_map(|v| v.split(';').next()?.split_once('='))
+            .find(|(k, _)| k.trim() == name)
+            .map(|(_, v)| v.trim())
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8(self.body.clone()).expect("Response body is not utf8")
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).expect("Response body is not json")
+    }
+
+    pub fn html(&self) -> Html {
+        Html::parse_document(&self.text())
+    }
+
+    /// Text of every element matching a CSS selector
+    pub fn select(&self, selector: &str) -> Vec<String> {
+        let selector = Selector::parse(selector).expect("Invalid selector");
+        self.html()
+            .select(&selector)
+            .map(|el| el.text().collect::<String>().trim().to_owned())
+            .collect()
+    }
+
+    /// Value of `attr` on every element matching a CSS selector
+    pub fn select_attr(&self, selector: &str, attr: &str) -> Vec<String> {
+        let selector = Selector::parse(selector).expect("Invalid selector");
+        self.html()
+            .select(&selector)
+            .filter_map(|el| el.value().attr(attr))
+            .map(ToOwned::to_owned)
+            .collect()
+    }
+
+    #[track_caller]
+    pub fn assert_status(&self, status: StatusCode) -> &Self {
+        assert_eq!(
+            self.status,
+            status,
+            "{}",
+            String::from_utf8_lossy(&self.body)
+        );
+        self
+    }
+}
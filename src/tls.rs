@@ -0,0 +1,258 @@
+//! HTTPS, terminated in-process with rustls
+//!
+//! astra only speaks plain HTTP, so TLS connections are served by the front
+//! end in [`crate::server`]. The certificate is re-read on SIGHUP, without
+//! dropping connections.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+use anyhow::{bail, Context};
+use hyper::http::HeaderValue;
+use hyper::{header, StatusCode};
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tracing::{error, info};
+
+/// Serves the certificate most recently loaded from `cert` and `key`
+pub struct CertResolver {
+    cert: PathBuf,
+    key: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    pub fn load(cert: &Path, key: &Path) -> anyhow::Result<Self> {
+        Ok(Self {
+            cert: cert.to_owned(),
+            key: key.to_owned(),
+            current: RwLock::new(Arc::new(load_certified_key(cert, key)?)),
+        })
+    }
+
+    /// Re-read the certificate; on failure the current one stays in use
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let certified_key = load_certified_key(&self.cert, &self.key)?;
+        *self.current.write().expect("locking failed") = Arc::new(certified_key);
+        Ok(())
+    }
+
+    /// Reload the certificate whenever the process gets SIGHUP
+    pub fn reload_on_sighup(self: &Arc<Self>) -> anyhow::Result<()> {
+        let mut signals = Signals::new([SIGHUP]).context("Failed to set SIGHUP handler")?;
+        let resolver = self.clone();
+        thread::spawn(move || {
+            for _ in signals.forever() {
+                match resolver.reload() {
+                    Ok(()) => info!(cert = %resolver.cert.display(), "Reloaded TLS certificate"),
+                    Err(err) => error!(
+                        err = format!("{err:#}"),
+                        "Failed to reload TLS certificate, keeping the current one"
+                    ),
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().expect("locking failed").clone())
+    }
+}
+
+fn load_certified_key(cert: &Path, key: &Path) -> anyhow::Result<CertifiedKey> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .with_context(|| format!("Failed to open {}", path.display()))
+    };
+
+    let certs = rustls_pemfile::certs(&mut open(cert)?)
+        .with_context(|| format!("Failed to parse certificates in {}", cert.display()))?;
+    if certs.is_empty() {
+        bail!("No certificates found in {}", cert.display());
+    }
+
+    let key_der = rustls_pemfile::read_all(&mut open(key)?)
+        .with_context(|| format!("Failed to parse private key in {}", key.display()))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::ECKey(der) => Some(der),
+            _ => None,
+        })
+        .with_context(|| format!("No private key found in {}", key.display()))?;
+    let signing_key = sign::any_supported_type(&PrivateKey(key_der))
+        .with_context(|| format!("Unsupported private key in {}", key.display()))?;
+
+    Ok(CertifiedKey::new(
+        certs.into_iter().map(Certificate).collect(),
+        signing_key,
+    ))
+}
+
+/// Acceptor for connections, using the certificate from `resolver`
+pub fn acceptor(resolver: Arc<CertResolver>) -> TlsAcceptor {
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    TlsAcceptor::from(Arc::new(config))
+}
+
+/// Plain HTTP listener sending everyone to the HTTPS one
+pub struct Redirect {
+    pub https_port: u16,
+}
+
+impl Redirect {
+    fn location(&self, req: &astra::Request) -> Option<String> {
+        let host = req.headers().get(header::HOST)?.to_str().ok()?;
+        // Drop the port, handling `[::1]:80`-style IPv6 hosts
+        let host = match host.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => host,
+            _ => host,
+        };
+        if host.is_empty() {
+            return None;
+        }
+        let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+        Some(match self.https_port {
+            443 => format!("https://{host}{path}"),
+            port => format!("https://{host}:{port}{path}"),
+        })
+    }
+}
+
+impl astra::Service for Redirect {
+    fn call(&self, req: astra::Request, _: astra::ConnectionInfo) -> astra::Response {
+        let mut resp = astra::Response::default();
+        match self
+            .location(&req)
+            .and_then(|location| HeaderValue::from_str(&location).ok())
+        {
+            Some(location) => {
+                *resp.status_mut() = StatusCode::MOVED_PERMANENTLY;
+                resp.headers_mut().insert(header::LOCATION, location);
+            }
+            None => *resp.status_mut() = StatusCode::BAD_REQUEST,
+        }
+        resp
+    }
+}
+
+#[test]
+fn redirect_test() {
+    let request = |host: &str, uri: &str| {
+        hyper::Request::builder()
+            .uri(uri)
+            .header(header::HOST, host)
+            .body(astra::Body::empty())
+            .unwrap()
+    };
+
+    let redirect = Redirect { https_port: 443 };
+    assert_eq!(
+        redirect
+            .location(&request("example.com:80", "/post/1?a=b"))
+            .as_deref(),
+        Some("https://example.com/post/1?a=b")
+    );
+    let redirect = Redirect { https_port: 8443 };
+    assert_eq!(
+        redirect.location(&request("[::1]", "/")).as_deref(),
+        Some("https://[::1]:8443/")
+    );
+    assert_eq!(redirect.location(&request(":80", "/")), None);
+}
+
+#[test]
+fn tls_test() {
+    use std::io::{self, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    use tokio_rustls::rustls::{self, ClientConfig, ClientConnection, RootCertStore};
+
+    use crate::config::TlsConfig;
+    use crate::test_client::TestClient;
+
+    let dir = std::env::temp_dir().join(format!("htmx-demo-tls-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    let new_cert = || {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+        Certificate(cert.serialize_der().unwrap())
+    };
+
+    let cert = new_cert();
+    let resolver = Arc::new(CertResolver::load(&cert_path, &key_path).unwrap());
+    let client = TestClient::with_config(|config| {
+        config.tls = Some(TlsConfig {
+            cert: cert_path.clone(),
+            key: key_path.clone(),
+            ..TlsConfig::default()
+        })
+    });
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    {
+        let (resolver, service) = (resolver.clone(), client.service().clone());
+        thread::spawn(move || {
+            crate::server::serve(
+                crate::server::Listener::Tcp(listener),
+                Some(acceptor(resolver)),
+                service,
+            )
+        });
+    }
+
+    let get = |trusted: &Certificate| -> io::Result<String> {
+        let mut roots = RootCertStore::empty();
+        roots.add(trusted).unwrap();
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let conn =
+            ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap()).unwrap();
+        let mut stream = rustls::StreamOwned::new(conn, TcpStream::connect(addr)?);
+        stream
+            .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+        let mut resp = vec![];
+        match stream.read_to_end(&mut resp) {
+            // Fine if the server closes without a close_notify
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof && !resp.is_empty() => {}
+            res => {
+                res?;
+            }
+        }
+        Ok(String::from_utf8_lossy(&resp).into_owned())
+    };
+
+    let resp = get(&cert).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 200"), "{resp}");
+    assert!(
+        resp.contains("strict-transport-security: max-age=31536000"),
+        "{resp}"
+    );
+
+    let new = new_cert();
+    resolver.reload().unwrap();
+    assert!(get(&cert).is_err());
+    assert!(get(&new).unwrap().starts_with("HTTP/1.1 200"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,4 +1,5 @@
 use std::fmt;
+use std::time::SystemTime;
 
 pub struct DisplayOption<T>(pub Option<T>);
 
@@ -13,3 +14,10 @@ where
         }
     }
 }
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,8 @@
+// htmx doesn't swap error responses by default. Ours carry `HX-Retarget`
+// pointing at `#flash` when they are meant to be shown, so let them through.
+document.addEventListener("htmx:beforeSwap", (e) => {
+  if (400 <= e.detail.xhr.status && e.detail.xhr.getResponseHeader("HX-Retarget")) {
+    e.detail.shouldSwap = true;
+    e.detail.isError = false;
+  }
+});